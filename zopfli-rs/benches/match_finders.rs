@@ -0,0 +1,61 @@
+// Copyright Anysphere Inc.
+// Compares the compression time of the match finders
+
+use std::time::{Duration, Instant};
+
+use zopfli_rs::{deflate, MatchFinderKind, Options};
+
+/// Pseudo-random data over the first alphabet lowercase letters.
+fn small_alphabet(size: usize, alphabet: u8) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size);
+    let mut x: u32 = 1;
+    for _ in 0..size {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        data.push(b'a' + (x >> 16) as u8 % alphabet);
+    }
+    data
+}
+
+/// Text-like data: words picked pseudo-randomly from a small vocabulary.
+fn text(size: usize) -> Vec<u8> {
+    let words = [
+        "the ", "quick ", "brown ", "fox ", "jumps ", "over ", "lazy ", "dog ", "and ", "runs\n",
+    ];
+    let mut data = Vec::with_capacity(size);
+    let mut x: u32 = 1;
+    while data.len() < size {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        data.extend_from_slice(words[(x >> 16) as usize % words.len()].as_bytes());
+    }
+    data.truncate(size);
+    data
+}
+
+/// Returns the fastest of a few compressions of data, and the compressed size.
+fn time(data: &[u8], match_finder: MatchFinderKind) -> (Duration, usize) {
+    let options = Options { numiterations: 5, match_finder, ..Options::default() };
+    let mut best = Duration::MAX;
+    let mut size = 0;
+    for _ in 0..3 {
+        let start = Instant::now();
+        size = deflate(&options, 2, data).unwrap().len();
+        best = best.min(start.elapsed());
+    }
+    (best, size)
+}
+
+fn main() {
+    let inputs = [
+        ("2-letter alphabet", small_alphabet(200000, 2)),
+        ("4-letter alphabet", small_alphabet(200000, 4)),
+        ("single byte", vec![b'a'; 200000]),
+        ("text", text(200000)),
+    ];
+    for (name, data) in &inputs {
+        for kind in [MatchFinderKind::HashChain, MatchFinderKind::BinaryTree] {
+            let (elapsed, size) = time(data, kind);
+            let ms = elapsed.as_secs_f64() * 1000.0;
+            println!("{:<18} {:<12} {:>8.1} ms {:>8} bytes", name, format!("{:?}", kind), ms, size);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright Anysphere Inc.
+// Command line tool for compressing files with Zopfli
+
+use std::fs;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+use zopfli_rs::{compress, Format, Options};
+
+const USAGE: &str = "Usage: zopfli [OPTION]... FILE...
+  -h    gives this help
+  -c    write the result on standard output, instead of disk filename + '.gz'
+  -v    verbose mode
+  --i#  perform # iterations (default 15). More gives more compression but is slower. Examples: --i10, --i50, --i1000
+  --gzip  output to gzip format (default)
+  --zlib  output to zlib format instead of gzip
+  --deflate  output to deflate format instead of gzip
+  --splitlast  ignored, left for backwards compatibility
+  --verify  decode the output again and fail if it does not match the input";
+
+/// Settings parsed from the command line.
+#[derive(Debug, Default)]
+struct Args {
+    options: Options,
+    format: Format,
+    output_to_stdout: bool,
+    help: bool,
+    filenames: Vec<String>,
+}
+
+/// Parses the arguments, excluding the program name. Like the C tool, flags
+/// apply to all files regardless of their position and unknown flags are
+/// ignored.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    for arg in args {
+        match arg.as_str() {
+            "-v" => parsed.options.verbose = true,
+            "-c" => parsed.output_to_stdout = true,
+            "-h" => parsed.help = true,
+            "--deflate" => parsed.format = Format::Deflate,
+            "--zlib" => parsed.format = Format::Zlib,
+            "--gzip" => parsed.format = Format::Gzip,
+            "--splitlast" => {} // Ignored.
+            "--verify" => parsed.options.verify = true,
+            _ if arg.starts_with("--i") && arg[3..].starts_with(|c: char| c.is_ascii_digit()) => {
+                let digits: String = arg[3..].chars().take_while(|c| c.is_ascii_digit()).collect();
+                parsed.options.numiterations = digits
+                    .parse()
+                    .map_err(|_| format!("invalid iteration count: {}", arg))?;
+            }
+            _ if arg.starts_with('-') => {}
+            _ => parsed.filenames.push(arg),
+        }
+    }
+    if parsed.options.numiterations < 1 {
+        return Err("must have 1 or more iterations".to_string());
+    }
+    Ok(parsed)
+}
+
+/// Compresses the file and writes the result to outfilename, or to standard
+/// output if outfilename is None.
+fn compress_file(options: &Options, format: Format, infilename: &str, outfilename: Option<&str>) -> Result<(), String> {
+    let input = fs::read(infilename).map_err(|e| format!("invalid filename: {}: {}", infilename, e))?;
+    let out = compress(options, format, &input).map_err(|e| format!("{}: {}", infilename, e))?;
+    match outfilename {
+        Some(outfilename) => fs::write(outfilename, &out)
+            .map_err(|e| format!("could not write {}: {}", outfilename, e)),
+        None => io::stdout()
+            .write_all(&out)
+            .and_then(|_| io::stdout().flush())
+            .map_err(|e| format!("could not write to standard output: {}", e)),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.help {
+        eprintln!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    if args.filenames.is_empty() {
+        eprintln!("Please provide filename\nFor help, type: zopfli -h");
+        return ExitCode::FAILURE;
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for filename in &args.filenames {
+        let outfilename = if args.output_to_stdout {
+            None
+        } else {
+            Some(format!("{}.{}", filename, args.format.extension()))
+        };
+        if args.options.verbose {
+            if let Some(outfilename) = &outfilename {
+                eprintln!("Saving to: {}", outfilename);
+            }
+        }
+        if let Err(e) = compress_file(&args.options, args.format, filename, outfilename.as_deref()) {
+            eprintln!("Error: {}", e);
+            status = ExitCode::FAILURE;
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let args = parse(&["a.txt"]).unwrap();
+        assert_eq!(args.format, Format::Gzip);
+        assert_eq!(args.options.numiterations, 15);
+        assert!(!args.output_to_stdout);
+        assert_eq!(args.filenames, vec!["a.txt"]);
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let args = parse(&["a.txt", "--i50", "--zlib", "-c", "-v", "--splitlast", "--verify", "b.txt"]).unwrap();
+        assert_eq!(args.format, Format::Zlib);
+        assert_eq!(args.options.numiterations, 50);
+        assert!(args.output_to_stdout);
+        assert!(args.options.verbose);
+        assert!(args.options.verify);
+        assert_eq!(args.filenames, vec!["a.txt", "b.txt"]);
+
+        // The last format flag wins.
+        assert_eq!(parse(&["--zlib", "--deflate"]).unwrap().format, Format::Deflate);
+    }
+
+    #[test]
+    fn test_parse_iterations() {
+        assert!(parse(&["--i0", "a.txt"]).is_err());
+        // Not an iteration flag, so ignored like any other unknown flag.
+        assert_eq!(parse(&["--ix", "a.txt"]).unwrap().options.numiterations, 15);
+    }
+}
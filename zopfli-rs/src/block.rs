@@ -3,6 +3,7 @@
 
 use crate::types::{LZ77Store, NUM_LL, NUM_D};
 use crate::huffman::{calculate_bit_lengths, optimize_huffman_for_rle};
+use crate::deflate::{encode_tree, BitWriter};
 use crate::symbols::{get_length_symbol, get_dist_symbol, get_length_symbol_extra_bits, get_dist_symbol_extra_bits};
 
 /// Gets the histogram of lit/len and dist symbols in the given range at a specific position.
@@ -12,17 +13,13 @@ fn lz77_get_histogram_at(lz77: &LZ77Store, lpos: usize, ll_counts: &mut [usize],
     let llpos = NUM_LL * (lpos / NUM_LL);
     let dpos = NUM_D * (lpos / NUM_D);
     
-    for i in 0..NUM_LL {
-        ll_counts[i] = lz77.ll_counts[llpos + i];
-    }
+    ll_counts[..NUM_LL].copy_from_slice(&lz77.ll_counts[llpos..llpos + NUM_LL]);
     
     for i in (lpos + 1)..(llpos + NUM_LL).min(lz77.size()) {
         ll_counts[lz77.ll_symbol[i] as usize] = ll_counts[lz77.ll_symbol[i] as usize].saturating_sub(1);
     }
     
-    for i in 0..NUM_D {
-        d_counts[i] = lz77.d_counts[dpos + i];
-    }
+    d_counts[..NUM_D].copy_from_slice(&lz77.d_counts[dpos..dpos + NUM_D]);
     
     for i in (lpos + 1)..(dpos + NUM_D).min(lz77.size()) {
         if lz77.dists[i] != 0 {
@@ -78,29 +75,19 @@ pub fn lz77_get_byte_range(lz77: &LZ77Store, lstart: usize, lend: usize) -> usiz
 
 /// Gets the fixed tree for DEFLATE fixed blocks.
 pub fn get_fixed_tree(ll_lengths: &mut [u32], d_lengths: &mut [u32]) {
-    for i in 0..144 {
-        ll_lengths[i] = 8;
-    }
-    for i in 144..256 {
-        ll_lengths[i] = 9;
-    }
-    for i in 256..280 {
-        ll_lengths[i] = 7;
-    }
-    for i in 280..288 {
-        ll_lengths[i] = 8;
-    }
-    for i in 0..32 {
-        d_lengths[i] = 5;
-    }
+    ll_lengths[..144].fill(8);
+    ll_lengths[144..256].fill(9);
+    ll_lengths[256..280].fill(7);
+    ll_lengths[280..288].fill(8);
+    d_lengths[..32].fill(5);
 }
 
 /// Ensures there are at least 2 distance codes to support buggy decoders.
 pub fn patch_distance_codes_for_buggy_decoders(d_lengths: &mut [u32]) {
     let mut num_dist_codes = 0;
     
-    for i in 0..30 {
-        if d_lengths[i] > 0 {
+    for &length in &d_lengths[..30] {
+        if length > 0 {
             num_dist_codes += 1;
         }
         if num_dist_codes >= 2 {
@@ -202,11 +189,18 @@ fn calculate_block_symbol_size(
     }
 }
 
-/// Stub function for tree size calculation (simplified for now).
-fn calculate_tree_size(_ll_lengths: &[u32], _d_lengths: &[u32]) -> usize {
-    // Simplified: assume average tree size
-    // Real implementation would call EncodeTree with all 8 combinations
-    500 // Approximate tree size in bits
+/// Gives the exact size of the tree, in bits, as it will be encoded in DEFLATE.
+pub fn calculate_tree_size(ll_lengths: &[u32], d_lengths: &[u32]) -> usize {
+    let mut result = 0;
+    
+    for i in 0..8 {
+        let size = encode_tree(ll_lengths, d_lengths, i & 1 != 0, i & 2 != 0, i & 4 != 0, None::<&mut BitWriter>);
+        if result == 0 || size < result {
+            result = size;
+        }
+    }
+    
+    result
 }
 
 /// Tries to optimize Huffman for RLE and returns size.
@@ -245,8 +239,11 @@ fn try_optimize_huffman_for_rle(
     (treesize + datasize) as f64
 }
 
-/// Calculates the bit lengths for the symbols for dynamic blocks.
-fn get_dynamic_lengths(
+/// Calculates the bit lengths for the symbols for dynamic blocks. Chooses bit
+/// lengths that give the smallest size of tree encoding + encoding of all the
+/// symbols to have smallest output size. Returns size of encoded tree and data
+/// in bits, not including the 3-bit block header.
+pub fn get_dynamic_lengths(
     lz77: &LZ77Store,
     lstart: usize,
     lend: usize,
@@ -334,9 +331,7 @@ mod tests {
         assert_eq!(ll_lengths[280], 8);
         assert_eq!(ll_lengths[287], 8);
         
-        for i in 0..32 {
-            assert_eq!(d_lengths[i], 5);
-        }
+        assert!(d_lengths[..32].iter().all(|&length| length == 5));
     }
     
     #[test]
@@ -355,11 +350,11 @@ mod tests {
         let mut store = LZ77Store::new(data);
         
         // Add some literal symbols
-        for i in 0..data.len() {
-            store.litlens.push(data[i] as u16);
+        for (i, &byte) in data.iter().enumerate() {
+            store.litlens.push(byte as u16);
             store.dists.push(0);
             store.pos.push(i);
-            store.ll_symbol.push(data[i] as u16);
+            store.ll_symbol.push(byte as u16);
             store.d_symbol.push(0);
         }
         
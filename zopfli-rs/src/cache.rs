@@ -4,7 +4,7 @@
 use crate::types::{LongestMatchCache, BlockState, CACHE_LENGTH, MIN_MATCH, MAX_MATCH};
 
 /// Returns the length up to which could be stored in the cache.
-pub fn max_cached_sublen(lmc: &LongestMatchCache, pos: usize, _length: usize) -> usize {
+pub(crate) fn max_cached_sublen(lmc: &LongestMatchCache, pos: usize, _length: usize) -> usize {
     let cache_start = CACHE_LENGTH * pos * 3;
     if lmc.sublen[cache_start + 1] == 0 && lmc.sublen[cache_start + 2] == 0 {
         return 0; // No sublen cached
@@ -13,7 +13,7 @@ pub fn max_cached_sublen(lmc: &LongestMatchCache, pos: usize, _length: usize) ->
 }
 
 /// Stores sublen array in the cache.
-pub fn sublen_to_cache(sublen: &[u16], pos: usize, length: usize, lmc: &mut LongestMatchCache) {
+pub(crate) fn sublen_to_cache(sublen: &[u16], pos: usize, length: usize, lmc: &mut LongestMatchCache) {
     let mut j = 0;
     let mut bestlength = 0;
     let cache_start = CACHE_LENGTH * pos * 3;
@@ -45,7 +45,7 @@ pub fn sublen_to_cache(sublen: &[u16], pos: usize, length: usize, lmc: &mut Long
 }
 
 /// Extracts sublen array from the cache.
-pub fn cache_to_sublen(lmc: &LongestMatchCache, pos: usize, length: usize, sublen: &mut [u16]) {
+pub(crate) fn cache_to_sublen(lmc: &LongestMatchCache, pos: usize, length: usize, sublen: &mut [u16]) {
     let maxlength = max_cached_sublen(lmc, pos, length);
     let mut prevlength = 0;
     let cache_start = CACHE_LENGTH * pos * 3;
@@ -59,9 +59,7 @@ pub fn cache_to_sublen(lmc: &LongestMatchCache, pos: usize, length: usize, suble
         let dist = lmc.sublen[cache_start + j * 3 + 1] as u16 
                    + 256 * lmc.sublen[cache_start + j * 3 + 2] as u16;
         
-        for i in prevlength..=len {
-            sublen[i] = dist;
-        }
+        sublen[prevlength..=len].fill(dist);
         
         if len == maxlength {
             break;
@@ -71,7 +69,7 @@ pub fn cache_to_sublen(lmc: &LongestMatchCache, pos: usize, length: usize, suble
 }
 
 /// Stores the found sublen, distance and length in the longest match cache, if possible.
-pub fn store_in_longest_match_cache(
+pub(crate) fn store_in_longest_match_cache(
     s: &mut BlockState,
     pos: usize,
     limit: usize,
@@ -108,7 +106,7 @@ pub fn store_in_longest_match_cache(
 /// Returns true if it got the values from the cache, false if not.
 /// Updates the limit value to a smaller one if possible with more limited
 /// information from the cache.
-pub fn try_get_from_longest_match_cache(
+pub(crate) fn try_get_from_longest_match_cache(
     s: &BlockState,
     pos: usize,
     limit: &mut usize,
@@ -186,8 +184,8 @@ mod tests {
         let mut sublen = vec![0u16; 259];
         
         // Fill sublen with some test data
-        for i in 3..=10 {
-            sublen[i] = (i * 10) as u16;
+        for (i, entry) in sublen.iter_mut().enumerate().take(11).skip(3) {
+            *entry = (i * 10) as u16;
         }
         
         // Store in cache
@@ -1,124 +1,956 @@
 // Copyright Anysphere Inc.
 // DEFLATE output generation
 
-use crate::types::{LZ77Store, Options, NUM_LL, NUM_D};
-use crate::block::get_fixed_tree;
-use crate::huffman::lengths_to_symbols;
+use std::io::{self, Write};
+
+use crate::types::{LZ77Store, Options, BlockState, Hash, NUM_LL, NUM_D, MASTER_BLOCK_SIZE, WINDOW_SIZE};
+use crate::block::{get_fixed_tree, get_dynamic_lengths, lz77_get_byte_range, calculate_block_size, calculate_block_size_auto_type};
+use crate::lz77::{lz77_greedy, lz77_optimal, lz77_optimal_fixed, append_lz77_store};
+use crate::split::{block_split, block_split_lz77};
+use crate::huffman::{calculate_bit_lengths, lengths_to_symbols, reverse_symbols};
+use crate::error::{check_options, check_range, ZopfliError};
+use crate::verify::verify_deflate;
 use crate::symbols::{get_length_symbol, get_dist_symbol, get_length_extra_bits, get_length_extra_bits_value, get_dist_extra_bits, get_dist_extra_bits_value};
 
-pub struct BitWriter {
-    pub out: Vec<u8>,
-    pub bp: u8, // bit position 0..7
+/// Bytes collected before the pending output is passed on to the sink.
+const SINK_BUFFER_SIZE: usize = 1 << 16;
+
+/// Writes a stream of bits LSB-first, as deflate packs them, to the sink W.
+/// Bits collect in a 64-bit accumulator and are moved out 32 at a time, and
+/// completed bytes are passed on to the sink in chunks of SINK_BUFFER_SIZE, so
+/// a file or socket sink does not need the whole output in memory.
+///
+/// Writing bits can not fail; the first I/O error of the sink is kept and
+/// returned by flush or finish, and nothing more is written after it.
+pub struct BitWriter<W: Write = Vec<u8>> {
+    sink: W,
+    /// Completed bytes not passed on to the sink yet.
+    buf: Vec<u8>,
+    /// Pending bits, the oldest in the least significant bit.
+    bits: u64,
+    /// Number of pending bits, less than 32 between calls.
+    nbits: u32,
+    /// Number of bytes passed on to the sink.
+    flushed: u64,
+    error: Option<io::Error>,
 }
 
-impl BitWriter {
+impl BitWriter<Vec<u8>> {
+    /// Creates a writer collecting the output in memory.
     pub fn new() -> Self {
-        Self { out: Vec::new(), bp: 0 }
+        Self::with_sink(Vec::new())
     }
-    
-    pub fn add_bit(&mut self, bit: u8) {
-        if self.bp == 0 { 
-            self.out.push(0); 
+
+    /// Moves all completed bytes into the output vector. Unlike flush this can
+    /// not fail. The bits of a partial last byte stay pending.
+    pub fn flush_bytes(&mut self) {
+        self.emit_bytes();
+        self.flushed += self.buf.len() as u64;
+        self.sink.append(&mut self.buf);
+    }
+
+    /// Returns the bits of a partial last byte, padded with zero bits, and how
+    /// many of them there are. After flush_bytes, these are the only bits not
+    /// in the output vector.
+    pub(crate) fn partial_byte(&self) -> (u8, u32) {
+        debug_assert!(self.nbits < 8, "partial_byte called before flush_bytes");
+        (self.bits as u8, self.nbits)
+    }
+
+    /// Pads the last byte with zero bits and returns the output.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.align_to_byte();
+        self.flush_bytes();
+        self.sink
+    }
+}
+
+impl Default for BitWriter<Vec<u8>> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> BitWriter<W> {
+    /// Creates a writer passing the output on to sink. Bits written before, e.g.
+    /// a container header already in a Vec sink, are not counted by
+    /// bit_position.
+    pub fn with_sink(sink: W) -> Self {
+        BitWriter { sink, buf: Vec::new(), bits: 0, nbits: 0, flushed: 0, error: None }
+    }
+
+    /// Writes the n lowest bits of value, least significant first. n can be at
+    /// most 32 and the higher bits of value must be zero.
+    #[inline]
+    pub fn write_bits(&mut self, value: u32, n: u32) {
+        debug_assert!(n <= 32 && (n == 32 || value >> n == 0));
+        self.bits |= (value as u64) << self.nbits;
+        self.nbits += n;
+        if self.nbits >= 32 {
+            self.buf.extend_from_slice(&(self.bits as u32).to_le_bytes());
+            self.bits >>= 32;
+            self.nbits -= 32;
+            if self.buf.len() >= SINK_BUFFER_SIZE {
+                self.flush_buf();
+            }
         }
-        let last = self.out.len() - 1;
-        self.out[last] |= (bit & 1) << self.bp;
-        self.bp = (self.bp + 1) & 7;
     }
-    
-    pub fn add_bits_le(&mut self, mut bits: u32, n: u8) {
-        for _ in 0..n {
-            let b = (bits & 1) as u8;
-            bits >>= 1;
-            self.add_bit(b);
+
+    /// Writes a Huffman code of the given length. Deflate stores Huffman codes
+    /// starting at their most significant bit, so code must already be bit
+    /// reversed, see reverse_symbols.
+    #[inline]
+    pub fn write_huffman(&mut self, code: u32, length: u32) {
+        self.write_bits(code, length);
+    }
+
+    /// Pads with zero bits up to the next byte boundary.
+    pub fn align_to_byte(&mut self) {
+        self.nbits = (self.nbits + 7) & !7;
+        self.emit_bytes();
+    }
+
+    /// Writes whole bytes, which must start at a byte boundary.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert!(self.nbits.is_multiple_of(8), "write_bytes is not byte aligned");
+        self.emit_bytes();
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= SINK_BUFFER_SIZE {
+            self.flush_buf();
         }
     }
-    
-    pub fn add_huff(&mut self, symbol: u32, length: u32) {
-        // Huffman codes are reversed bit order
-        let mut sym = symbol;
-        for _ in 0..length {
-            self.add_bit((sym >> (length - 1)) as u8);
-            sym = (sym << 1) & ((1 << length) - 1);
+
+    /// Returns the number of bits written so far.
+    pub fn bit_position(&self) -> u64 {
+        (self.flushed + self.buf.len() as u64) * 8 + self.nbits as u64
+    }
+
+    /// Returns a reference to the sink.
+    pub fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    /// Returns a mutable reference to the sink. Bytes the writer has not
+    /// flushed yet are not in it.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    /// Passes all completed bytes on to the sink and flushes it. The bits of a
+    /// partial last byte stay pending. Returns the first error of the sink.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.emit_bytes();
+        self.flush_buf();
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.sink.flush()
+    }
+
+    /// Takes the first error of the sink, if it failed, so that the caller can
+    /// stop producing output that would not be written anyway.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Pads the last byte with zero bits, flushes everything to the sink and
+    /// returns it.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.align_to_byte();
+        self.flush()?;
+        Ok(self.sink)
+    }
+
+    /// Moves the whole bytes of the accumulator to buf.
+    fn emit_bytes(&mut self) {
+        while self.nbits >= 8 {
+            self.buf.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    /// Writes buf to the sink, unless an earlier write failed.
+    fn flush_buf(&mut self) {
+        if self.error.is_none() {
+            if let Err(e) = self.sink.write_all(&self.buf) {
+                self.error = Some(e);
+            }
         }
+        self.flushed += self.buf.len() as u64;
+        self.buf.clear();
     }
 }
 
-fn add_lz77_data(
+/// Adds an uncompressed (stored) block. Since a stored block can be max 65535
+/// bytes in size, it actually adds multiple blocks if needed.
+pub(crate) fn add_non_compressed_block<W: Write>(
+    _options: &Options,
+    final_block: bool,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    bw: &mut BitWriter<W>,
+) {
+    let mut pos = instart;
+    loop {
+        let blocksize = (inend - pos).min(65535);
+        let currentfinal = pos + blocksize >= inend;
+        let nlen = !(blocksize as u16);
+        
+        bw.write_bits((final_block && currentfinal) as u32, 1);
+        // BTYPE 00
+        bw.write_bits(0, 2);
+        
+        // Any bits of input up to the next byte boundary are ignored.
+        bw.align_to_byte();
+        
+        bw.write_bits(blocksize as u32, 16);
+        bw.write_bits(nlen as u32, 16);
+        
+        bw.write_bytes(&input[pos..pos + blocksize]);
+        
+        if currentfinal {
+            break;
+        }
+        pos += blocksize;
+    }
+}
+
+/// Adds all lit/len and dist codes from the lists as huffman symbols, which must
+/// be bit reversed already. Does not add end code 256. expected_data_size is
+/// the uncompressed block size, used for assert, but you can set it to 0 to not
+/// do the assertion.
+#[allow(clippy::too_many_arguments)] // Mirrors the C signature.
+fn add_lz77_data<W: Write>(
     lz77: &LZ77Store,
     lstart: usize,
     lend: usize,
+    expected_data_size: usize,
     ll_symbols: &[u32],
     ll_lengths: &[u32],
     d_symbols: &[u32],
     d_lengths: &[u32],
-    bw: &mut BitWriter,
+    bw: &mut BitWriter<W>,
 ) {
+    let mut testlength = 0;
+    
     for i in lstart..lend {
         let dist = lz77.dists[i] as usize;
         let litlen = lz77.litlens[i] as usize;
         
         if dist == 0 {
             // Literal
-            bw.add_huff(ll_symbols[litlen], ll_lengths[litlen]);
+            debug_assert!(litlen < 256);
+            debug_assert!(ll_lengths[litlen] > 0);
+            bw.write_huffman(ll_symbols[litlen], ll_lengths[litlen]);
+            testlength += 1;
         } else {
             // Match
             let ls = get_length_symbol(litlen);
-            bw.add_huff(ll_symbols[ls], ll_lengths[ls]);
-            let lbits = get_length_extra_bits(litlen) as u8;
+            debug_assert!(ll_lengths[ls] > 0);
+            bw.write_huffman(ll_symbols[ls], ll_lengths[ls]);
+            let lbits = get_length_extra_bits(litlen) as u32;
             let lval = get_length_extra_bits_value(litlen) as u32;
-            if lbits > 0 { 
-                bw.add_bits_le(lval, lbits); 
-            }
+            bw.write_bits(lval, lbits);
             
             let ds = get_dist_symbol(dist);
-            bw.add_huff(d_symbols[ds], d_lengths[ds]);
-            let dbits = get_dist_extra_bits(dist) as u8;
+            debug_assert!(d_lengths[ds] > 0);
+            bw.write_huffman(d_symbols[ds], d_lengths[ds]);
+            let dbits = get_dist_extra_bits(dist) as u32;
             let dval = get_dist_extra_bits_value(dist) as u32;
-            if dbits > 0 { 
-                bw.add_bits_le(dval, dbits); 
-            }
+            bw.write_bits(dval, dbits);
+            testlength += litlen;
         }
     }
     
-    // End symbol 256
-    bw.add_huff(ll_symbols[256], ll_lengths[256]);
+    debug_assert!(expected_data_size == 0 || testlength == expected_data_size);
 }
 
-pub fn deflate_fixed_block(lz77: &LZ77Store, lstart: usize, lend: usize, final_block: bool) -> Vec<u8> {
-    let mut bw = BitWriter::new();
+/// Encodes the Huffman tree and returns how many bits its encoding takes. If bw
+/// is None, only returns the size and runs faster.
+pub fn encode_tree<W: Write>(
+    ll_lengths: &[u32],
+    d_lengths: &[u32],
+    use_16: bool,
+    use_17: bool,
+    use_18: bool,
+    bw: Option<&mut BitWriter<W>>,
+) -> usize {
+    // The order in which code length code lengths are encoded as per deflate.
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+    
+    let size_only = bw.is_none();
+    // Runlength encoded version of lengths of litlen and dist trees.
+    let mut rle: Vec<u32> = Vec::new();
+    // Extra bits for rle values 16, 17 and 18.
+    let mut rle_bits: Vec<u32> = Vec::new();
+    let mut hlit = 29; // 286 - 257
+    let mut hdist = 29; // 32 - 1, but gzip does not like hdist > 29.
+    let mut clcounts = [0usize; 19];
+    let mut clcl = [0u32; 19]; // Code length code lengths.
+    let mut clsymbols = [0u32; 19];
+    
+    // Trim zeros.
+    while hlit > 0 && ll_lengths[257 + hlit - 1] == 0 {
+        hlit -= 1;
+    }
+    while hdist > 0 && d_lengths[1 + hdist - 1] == 0 {
+        hdist -= 1;
+    }
+    let hlit2 = hlit + 257;
+    
+    // Total amount of literal, length, distance codes.
+    let lld_total = hlit2 + hdist + 1;
+    let length_at = |j: usize| if j < hlit2 { ll_lengths[j] } else { d_lengths[j - hlit2] };
+    
+    let mut i = 0;
+    while i < lld_total {
+        // This is an encoding of a huffman tree, so now the length is a symbol
+        let symbol = length_at(i) as usize;
+        let mut count = 1;
+        if use_16 || (symbol == 0 && (use_17 || use_18)) {
+            let mut j = i + 1;
+            while j < lld_total && symbol == length_at(j) as usize {
+                count += 1;
+                j += 1;
+            }
+        }
+        i += count - 1;
+        
+        // Repetitions of zeroes
+        if symbol == 0 && count >= 3 {
+            if use_18 {
+                while count >= 11 {
+                    let count2 = count.min(138);
+                    if !size_only {
+                        rle.push(18);
+                        rle_bits.push((count2 - 11) as u32);
+                    }
+                    clcounts[18] += 1;
+                    count -= count2;
+                }
+            }
+            if use_17 {
+                while count >= 3 {
+                    let count2 = count.min(10);
+                    if !size_only {
+                        rle.push(17);
+                        rle_bits.push((count2 - 3) as u32);
+                    }
+                    clcounts[17] += 1;
+                    count -= count2;
+                }
+            }
+        }
+        
+        // Repetitions of any symbol
+        if use_16 && count >= 4 {
+            count -= 1; // Since the first one is hardcoded.
+            clcounts[symbol] += 1;
+            if !size_only {
+                rle.push(symbol as u32);
+                rle_bits.push(0);
+            }
+            while count >= 3 {
+                let count2 = count.min(6);
+                if !size_only {
+                    rle.push(16);
+                    rle_bits.push((count2 - 3) as u32);
+                }
+                clcounts[16] += 1;
+                count -= count2;
+            }
+        }
+        
+        // No or insufficient repetition
+        clcounts[symbol] += count;
+        if !size_only {
+            for _ in 0..count {
+                rle.push(symbol as u32);
+                rle_bits.push(0);
+            }
+        }
+        
+        i += 1;
+    }
+    
+    calculate_bit_lengths(&clcounts, 19, 7, &mut clcl);
+    if !size_only {
+        lengths_to_symbols(&clcl, 19, 7, &mut clsymbols);
+        reverse_symbols(&clcl, 19, &mut clsymbols);
+    }
+    
+    let mut hclen = 15;
+    // Trim zeros.
+    while hclen > 0 && clcounts[ORDER[hclen + 4 - 1]] == 0 {
+        hclen -= 1;
+    }
     
-    // BFINAL bit
-    bw.add_bit(if final_block {1} else {0});
+    if let Some(bw) = bw {
+        bw.write_bits(hlit as u32, 5);
+        bw.write_bits(hdist as u32, 5);
+        bw.write_bits(hclen as u32, 4);
+        
+        for &sym in ORDER.iter().take(hclen + 4) {
+            bw.write_bits(clcl[sym], 3);
+        }
+        
+        for (&sym, &bits) in rle.iter().zip(rle_bits.iter()) {
+            bw.write_huffman(clsymbols[sym as usize], clcl[sym as usize]);
+            // Extra bits.
+            match sym {
+                16 => bw.write_bits(bits, 2),
+                17 => bw.write_bits(bits, 3),
+                18 => bw.write_bits(bits, 7),
+                _ => {}
+            }
+        }
+    }
     
-    // BTYPE = 01 (fixed huffman)
-    bw.add_bit(1);
-    bw.add_bit(0);
+    let mut result_size = 14; // hlit, hdist, hclen bits
+    result_size += (hclen + 4) * 3; // clcl bits
+    for i in 0..19 {
+        result_size += clcl[i] as usize * clcounts[i];
+    }
+    // Extra bits.
+    result_size += clcounts[16] * 2;
+    result_size += clcounts[17] * 3;
+    result_size += clcounts[18] * 7;
     
+    result_size
+}
+
+/// Writes the dynamic Huffman tree, using the combination of RLE symbols 16, 17
+/// and 18 that gives the smallest encoding.
+fn add_dynamic_tree<W: Write>(ll_lengths: &[u32], d_lengths: &[u32], bw: &mut BitWriter<W>) {
+    let mut best = 0;
+    let mut bestsize = 0;
+    
+    for i in 0..8 {
+        let size = encode_tree(ll_lengths, d_lengths, i & 1 != 0, i & 2 != 0, i & 4 != 0, None::<&mut BitWriter<W>>);
+        if bestsize == 0 || size < bestsize {
+            bestsize = size;
+            best = i;
+        }
+    }
+    
+    encode_tree(ll_lengths, d_lengths, best & 1 != 0, best & 2 != 0, best & 4 != 0, Some(bw));
+}
+
+/// Adds a deflate block with the given LZ77 data to the output.
+/// btype: the block type, 0 (stored), 1 (fixed) or 2 (dynamic)
+/// final_block: whether to set the "final" bit on this block, must be the last block
+/// lstart: where to start in the LZ77 data
+/// lend: where to end in the LZ77 data (not inclusive)
+/// expected_data_size: the uncompressed block size, used for assert, but you can
+/// set it to 0 to not do the assertion.
+#[allow(clippy::too_many_arguments)] // Mirrors the C signature.
+pub(crate) fn add_lz77_block<W: Write>(
+    options: &Options,
+    btype: i32,
+    final_block: bool,
+    lz77: &LZ77Store,
+    lstart: usize,
+    lend: usize,
+    expected_data_size: usize,
+    bw: &mut BitWriter<W>,
+) {
     let mut ll_lengths = vec![0u32; NUM_LL];
     let mut d_lengths = vec![0u32; NUM_D];
-    get_fixed_tree(&mut ll_lengths, &mut d_lengths);
+    let mut ll_symbols = vec![0u32; NUM_LL];
+    let mut d_symbols = vec![0u32; NUM_D];
+    
+    assert!((0..=2).contains(&btype), "unsupported block type {}", btype);
+    
+    if btype == 0 {
+        let length = lz77_get_byte_range(lz77, lstart, lend);
+        let pos = if lstart == lend { 0 } else { lz77.pos[lstart] };
+        let end = pos + length;
+        add_non_compressed_block(options, final_block, lz77.data, pos, end, bw);
+        return;
+    }
+    
+    bw.write_bits(final_block as u32, 1);
+    bw.write_bits(btype as u32, 2);
+    
+    if btype == 1 {
+        // Fixed block.
+        get_fixed_tree(&mut ll_lengths, &mut d_lengths);
+    } else {
+        // Dynamic block.
+        get_dynamic_lengths(lz77, lstart, lend, &mut ll_lengths, &mut d_lengths);
+        
+        let detect_tree_size = bw.bit_position();
+        add_dynamic_tree(&ll_lengths, &d_lengths, bw);
+        if options.verbose {
+            eprintln!("treesize: {}", (bw.bit_position() - detect_tree_size) / 8);
+        }
+    }
+    
+    lengths_to_symbols(&ll_lengths, NUM_LL, 15, &mut ll_symbols);
+    lengths_to_symbols(&d_lengths, NUM_D, 15, &mut d_symbols);
+    reverse_symbols(&ll_lengths, NUM_LL, &mut ll_symbols);
+    reverse_symbols(&d_lengths, NUM_D, &mut d_symbols);
+    
+    let detect_block_size = bw.bit_position();
+    add_lz77_data(lz77, lstart, lend, expected_data_size,
+                  &ll_symbols, &ll_lengths, &d_symbols, &d_lengths, bw);
+    // End symbol.
+    bw.write_huffman(ll_symbols[256], ll_lengths[256]);
+    
+    if options.verbose {
+        let mut uncompressed_size = 0;
+        for i in lstart..lend {
+            uncompressed_size += if lz77.dists[i] == 0 { 1 } else { lz77.litlens[i] as usize };
+        }
+        let compressed_size = (bw.bit_position() - detect_block_size) / 8;
+        eprintln!("compressed block size: {} ({}k) (unc: {})",
+                  compressed_size, compressed_size / 1024, uncompressed_size);
+    }
+}
+
+/// Chooses whichever of the stored, fixed and dynamic block types gives the
+/// smallest block for the given LZ77 data. If the fixed type wins after
+/// recalculating the LZ77 data for the fixed tree, the new store is returned
+/// along with it and must be written instead.
+fn choose_block_type<'a>(
+    options: &Options,
+    lz77: &LZ77Store<'a>,
+    lstart: usize,
+    lend: usize,
+) -> Result<(i32, Option<LZ77Store<'a>>), ZopfliError> {
+    if lstart == lend {
+        // Smallest empty block is represented by fixed block
+        return Ok((1, None));
+    }
+
+    let uncompressedcost = calculate_block_size(lz77, lstart, lend, 0);
+    let mut fixedcost = calculate_block_size(lz77, lstart, lend, 1);
+    let dyncost = calculate_block_size(lz77, lstart, lend, 2);
+    
+    // Whether to perform the expensive calculation of creating an optimal block
+    // with fixed huffman tree to check if smaller. Only do this for small blocks or
+    // blocks which already are pretty good with fixed huffman tree. Greedy
+    // options skip it, since it is an optimal parse.
+    let expensivefixed = !options.greedy && (lz77.size() < 1000 || fixedcost <= dyncost * 1.1);
+    
+    let mut fixedstore = LZ77Store::new(lz77.data);
+    if expensivefixed {
+        // Recalculate the LZ77 with lz77_optimal_fixed
+        let instart = lz77.pos[lstart];
+        let inend = instart + lz77_get_byte_range(lz77, lstart, lend);
+        
+        let mut s = BlockState::new(options, instart, inend, true);
+        lz77_optimal_fixed(&mut s, lz77.data, instart, inend, &mut fixedstore)?;
+        fixedcost = calculate_block_size(&fixedstore, 0, fixedstore.size(), 1);
+    }
     
-    let mut ll_syms = vec![0u32; NUM_LL];
-    let mut d_syms = vec![0u32; NUM_D];
-    lengths_to_symbols(&ll_lengths, NUM_LL, 15, &mut ll_syms);
-    lengths_to_symbols(&d_lengths, NUM_D, 15, &mut d_syms);
+    if uncompressedcost < fixedcost && uncompressedcost < dyncost {
+        Ok((0, None))
+    } else if fixedcost < dyncost {
+        if expensivefixed {
+            Ok((1, Some(fixedstore)))
+        } else {
+            Ok((1, None))
+        }
+    } else {
+        Ok((2, None))
+    }
+}
+
+/// Adds a deflate block with the given LZ77 data to the output, using whichever
+/// of the stored, fixed and dynamic block types gives the smallest result.
+/// Returns InvalidOptions if lstart..lend is not a range of the LZ77 data.
+pub fn add_lz77_block_auto_type<W: Write>(
+    options: &Options,
+    final_block: bool,
+    lz77: &LZ77Store,
+    lstart: usize,
+    lend: usize,
+    expected_data_size: usize,
+    bw: &mut BitWriter<W>,
+) -> Result<(), ZopfliError> {
+    if lstart > lend || lend > lz77.size() {
+        return Err(ZopfliError::InvalidOptions("range is out of bounds of the LZ77 data"));
+    }
+    match choose_block_type(options, lz77, lstart, lend)? {
+        (btype, Some(store)) => {
+            add_lz77_block(options, btype, final_block, &store, 0, store.size(), expected_data_size, bw);
+        }
+        (btype, None) => {
+            add_lz77_block(options, btype, final_block, lz77, lstart, lend, expected_data_size, bw);
+        }
+    }
+    Ok(())
+}
+
+/// A deflate block of a master block, with its type already chosen.
+struct PlannedBlock<'a> {
+    btype: i32,
+    /// Range in the LZ77 data of the master block.
+    lstart: usize,
+    lend: usize,
+    /// LZ77 data recalculated for the fixed tree, written instead of the range.
+    fixedstore: Option<LZ77Store<'a>>,
+}
+
+/// The deflate blocks of one master block with dynamic block type selection.
+/// Everything expensive is decided here, and none of it depends on the bit
+/// position the master block will be written at, so master blocks can be
+/// planned independently and written in order afterwards.
+pub struct DeflatePartPlan<'a> {
+    lz77: LZ77Store<'a>,
+    blocks: Vec<PlannedBlock<'a>>,
+}
+
+/// Runs lz77_optimal, or lz77_greedy if options.greedy is set, on each of the
+/// byte ranges, each with its own block state, and returns the stores in the
+/// order of the ranges. With options.threads larger than 1, the ranges are
+/// distributed over that many threads; since the ranges are optimized
+/// independently the result does not depend on it.
+fn optimize_subblocks<'a>(
+    options: &Options,
+    input: &'a [u8],
+    ranges: &[(usize, usize)],
+) -> Result<Vec<LZ77Store<'a>>, ZopfliError> {
+    let optimize = |&(start, end): &(usize, usize)| {
+        let mut s = BlockState::new(options, start, end, true);
+        let mut store = LZ77Store::new(input);
+        if options.greedy {
+            let mut finder = options.match_finder.create();
+            lz77_greedy(&mut s, input, start, end, &mut store, &mut *finder)?;
+        } else {
+            lz77_optimal(&mut s, input, start, end, options.numiterations, &mut store)?;
+        }
+        Ok(store)
+    };
+    
+    let nthreads = options.threads.min(ranges.len());
+    if nthreads <= 1 {
+        return ranges.iter().map(optimize).collect();
+    }
     
-    add_lz77_data(lz77, lstart, lend, &ll_syms, &ll_lengths, &d_syms, &d_lengths, &mut bw);
+    // Thread k optimizes ranges k, k + nthreads, k + 2 * nthreads, ...
+    let results: Vec<Vec<LZ77Store<'a>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..nthreads)
+            .map(|k| {
+                let optimize = &optimize;
+                scope.spawn(move || ranges.iter().skip(k).step_by(nthreads).map(optimize).collect())
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or(Err(ZopfliError::ThreadPanicked)))
+            .collect::<Result<_, ZopfliError>>()
+    })?;
     
-    bw.out
+    // Take the stores round robin from the threads, in the order of the ranges.
+    // Earlier threads have at least as many stores as later ones, so the first
+    // thread that runs out comes after the last range.
+    let mut stores = Vec::with_capacity(ranges.len());
+    let mut iters: Vec<_> = results.into_iter().map(Vec::into_iter).collect();
+    for k in (0..nthreads).cycle() {
+        match iters[k].next() {
+            Some(store) => stores.push(store),
+            None => break,
+        }
+    }
+    Ok(stores)
+}
+
+/// Does the block splitting, LZ77 optimization and block type selection of
+/// deflate_part for btype 2, without writing anything.
+/// Returns InvalidOptions if instart..inend is not a range of input.
+pub fn plan_deflate_part<'a>(
+    options: &Options,
+    input: &'a [u8],
+    instart: usize,
+    inend: usize,
+) -> Result<DeflatePartPlan<'a>, ZopfliError> {
+    // byte coordinates rather than lz77 index
+    let splitpoints_uncompressed = if options.blocksplitting {
+        block_split(options, input, instart, inend, options.blocksplittingmax)?
+    } else {
+        Vec::new()
+    };
+    let npoints = splitpoints_uncompressed.len();
+    let mut splitpoints = Vec::with_capacity(npoints);
+    let mut totalcost = 0.0;
+    
+    let mut lz77 = LZ77Store::new(input);
+    
+    let ranges: Vec<(usize, usize)> = (0..=npoints)
+        .map(|i| {
+            let start = if i == 0 { instart } else { splitpoints_uncompressed[i - 1] };
+            let end = if i == npoints { inend } else { splitpoints_uncompressed[i] };
+            (start, end)
+        })
+        .collect();
+    let stores = optimize_subblocks(options, input, &ranges)?;
+    
+    for (i, store) in stores.iter().enumerate() {
+        totalcost += calculate_block_size_auto_type(store, 0, store.size());
+        
+        append_lz77_store(store, &mut lz77);
+        if i < npoints {
+            splitpoints.push(lz77.size());
+        }
+    }
+    
+    // Second block splitting attempt
+    if options.blocksplitting && npoints > 1 {
+        let splitpoints2 = block_split_lz77(options, &lz77, options.blocksplittingmax);
+        let npoints2 = splitpoints2.len();
+        let mut totalcost2 = 0.0;
+        
+        for i in 0..=npoints2 {
+            let start = if i == 0 { 0 } else { splitpoints2[i - 1] };
+            let end = if i == npoints2 { lz77.size() } else { splitpoints2[i] };
+            totalcost2 += calculate_block_size_auto_type(&lz77, start, end);
+        }
+        
+        if totalcost2 < totalcost {
+            splitpoints = splitpoints2;
+        }
+    }
+    
+    let npoints = splitpoints.len();
+    let mut blocks = Vec::with_capacity(npoints + 1);
+    for i in 0..=npoints {
+        let lstart = if i == 0 { 0 } else { splitpoints[i - 1] };
+        let lend = if i == npoints { lz77.size() } else { splitpoints[i] };
+        let (btype, fixedstore) = choose_block_type(options, &lz77, lstart, lend)?;
+        blocks.push(PlannedBlock { btype, lstart, lend, fixedstore });
+    }
+    Ok(DeflatePartPlan { lz77, blocks })
 }
 
-pub fn deflate_greedy_fixed(input: &[u8]) -> Vec<u8> {
-    use crate::lz77::lz77_greedy;
-    use crate::types::{Hash, BlockState};
+/// Writes the deflate blocks of a planned master block. If final_block is true,
+/// the final bit is set on the last block.
+pub fn add_deflate_part_plan<W: Write>(options: &Options, final_block: bool, plan: &DeflatePartPlan, bw: &mut BitWriter<W>) {
+    let nblocks = plan.blocks.len();
+    for (i, block) in plan.blocks.iter().enumerate() {
+        let last = i + 1 == nblocks && final_block;
+        match &block.fixedstore {
+            Some(store) => add_lz77_block(options, block.btype, last, store, 0, store.size(), 0, bw),
+            None => add_lz77_block(options, block.btype, last, &plan.lz77, block.lstart, block.lend, 0, bw),
+        }
+    }
+}
+
+/// Deflate a part, to allow deflate() to use multiple master blocks if needed.
+/// It is possible to call this function multiple times in a row, shifting
+/// instart and inend to next bytes of the data. If instart is larger than 0, then
+/// previous bytes are used as the initial dictionary for LZ77.
+/// This function will usually output multiple deflate blocks. If final_block is
+/// true, then the final bit will be set on the last block.
+/// Returns InvalidOptions if instart..inend is not a range of input.
+pub(crate) fn deflate_part<W: Write>(
+    options: &Options,
+    btype: i32,
+    final_block: bool,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    bw: &mut BitWriter<W>,
+) -> Result<(), ZopfliError> {
+    check_range(input, instart, inend)?;
+    // If btype=2 is specified, it tries all block types. If a lesser btype is
+    // given, then however it forces that one. Neither of the lesser types needs
+    // block splitting as they have no dynamic huffman trees.
+    if btype == 0 {
+        add_non_compressed_block(options, final_block, input, instart, inend, bw);
+        return Ok(());
+    } else if btype == 1 {
+        let mut store = LZ77Store::new(input);
+        let mut s = BlockState::new(options, instart, inend, true);
+        
+        lz77_optimal_fixed(&mut s, input, instart, inend, &mut store)?;
+        add_lz77_block(options, btype, final_block, &store, 0, store.size(), 0, bw);
+        return Ok(());
+    }
     
+    let plan = plan_deflate_part(options, input, instart, inend)?;
+    add_deflate_part_plan(options, final_block, &plan, bw);
+    Ok(())
+}
+
+/// Compresses data[instart..] in master blocks of master_block_size bytes,
+/// setting the final bit on the last one. Stops with its error once the sink
+/// of bw has failed. With btype 2 and options.threads
+/// larger than 1, up to that many master blocks are planned concurrently, each
+/// with its own state, and written in order, giving the same output as the
+/// single-threaded path.
+fn deflate_master_blocks<W: Write>(
+    options: &Options,
+    btype: i32,
+    data: &[u8],
+    instart: usize,
+    master_block_size: usize,
+    bw: &mut BitWriter<W>,
+) -> Result<(), ZopfliError> {
+    let insize = data.len();
+    let mut ranges = Vec::new();
+    let mut i = instart;
+    loop {
+        let masterfinal = i + master_block_size >= insize;
+        let size = if masterfinal { insize - i } else { master_block_size };
+        ranges.push((i, i + size));
+        i += size;
+        if i >= insize {
+            break;
+        }
+    }
+    let nranges = ranges.len();
+
+    if options.threads <= 1 || btype != 2 || nranges == 1 {
+        for (k, &(start, end)) in ranges.iter().enumerate() {
+            deflate_part(options, btype, k + 1 == nranges, data, start, end, bw)?;
+            if let Some(e) = bw.take_error() {
+                return Err(e.into());
+            }
+        }
+        return Ok(());
+    }
+
+    // Plan a batch of master blocks at a time, to bound the memory held by
+    // plans that are waiting to be written. The threads are already busy, so
+    // each master block optimizes its subblocks sequentially.
+    let inner = Options { threads: 1, ..options.clone() };
+    let inner = &inner;
+    for (batch, chunk) in ranges.chunks(options.threads).enumerate() {
+        let plans: Vec<DeflatePartPlan> = std::thread::scope(|scope| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|&(start, end)| scope.spawn(move || plan_deflate_part(inner, data, start, end)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or(Err(ZopfliError::ThreadPanicked)))
+                .collect::<Result<_, ZopfliError>>()
+        })?;
+        for (k, plan) in plans.iter().enumerate() {
+            let masterfinal = batch * options.threads + k + 1 == nranges;
+            add_deflate_part_plan(options, masterfinal, plan, bw);
+        }
+        if let Some(e) = bw.take_error() {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Like deflate_master_blocks for all of input, with the dictionary of at most
+/// WINDOW_SIZE bytes in front of it. Since master blocks are at least that
+/// large, only the first one can refer back into the dictionary, so only that
+/// one is compressed from a copy of the input that includes it.
+fn deflate_master_blocks_with_dictionary<W: Write>(
+    options: &Options,
+    btype: i32,
+    dictionary: &[u8],
+    input: &[u8],
+    master_block_size: usize,
+    bw: &mut BitWriter<W>,
+) -> Result<(), ZopfliError> {
+    debug_assert!(dictionary.len() <= WINDOW_SIZE && master_block_size >= WINDOW_SIZE);
+    if dictionary.is_empty() {
+        return deflate_master_blocks(options, btype, input, 0, master_block_size, bw);
+    }
+    let first = input.len().min(master_block_size);
+    let mut head = Vec::new();
+    head.try_reserve_exact(dictionary.len() + first).map_err(|_| ZopfliError::InputTooLarge)?;
+    head.extend_from_slice(dictionary);
+    head.extend_from_slice(&input[..first]);
+    if first == input.len() {
+        return deflate_master_blocks(options, btype, &head, dictionary.len(), master_block_size, bw);
+    }
+    deflate_part(options, btype, false, &head, dictionary.len(), head.len(), bw)?;
+    if let Some(e) = bw.take_error() {
+        return Err(e.into());
+    }
+    deflate_master_blocks(options, btype, input, first, master_block_size, bw)
+}
+
+/// Compresses according to the deflate specification and returns the compressed
+/// result. The input is processed in master blocks of MASTER_BLOCK_SIZE bytes,
+/// each using the preceding data as dictionary, and the final bit is set on the
+/// last deflate block.
+/// btype: the deflate block type. Use 2 for best compression.
+///   -0: non compressed blocks (00)
+///   -1: blocks with fixed tree (01)
+///   -2: blocks with dynamic tree (10)
+/// If options.verify is set, the output is decoded again and an error is
+/// returned if it does not reproduce the input.
+pub fn deflate(options: &Options, btype: i32, input: &[u8]) -> Result<Vec<u8>, ZopfliError> {
+    deflate_with_dictionary(options, btype, &[], input)
+}
+
+/// Like deflate, but the LZ77 matches may refer back into the given preset
+/// dictionary, of which only the last WINDOW_SIZE bytes can be used. The
+/// decoder must be given the same dictionary.
+pub fn deflate_with_dictionary(
+    options: &Options,
+    btype: i32,
+    dictionary: &[u8],
+    input: &[u8],
+) -> Result<Vec<u8>, ZopfliError> {
+    check_options(options, btype)?;
+    let dictionary = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..];
+    let mut bw = BitWriter::new();
+    deflate_master_blocks_with_dictionary(options, btype, dictionary, input, MASTER_BLOCK_SIZE, &mut bw)?;
+    let out = bw.into_bytes();
+    if options.verbose {
+        print_deflate_summary(input.len(), out.len());
+    }
+    if options.verify {
+        verify_deflate(dictionary, &out, input)?;
+    }
+    Ok(out)
+}
+
+/// Like deflate, but passes the compressed data on to out while it is being
+/// produced instead of collecting all of it in memory, and returns out when
+/// done. If options.verify is set, the output has to be decoded before any of
+/// it is written, so it is collected in memory after all.
+pub fn deflate_to<W: Write>(options: &Options, btype: i32, input: &[u8], mut out: W) -> Result<W, ZopfliError> {
+    if options.verify {
+        out.write_all(&deflate(options, btype, input)?)?;
+        return Ok(out);
+    }
+    check_options(options, btype)?;
+    let mut bw = BitWriter::with_sink(out);
+    deflate_master_blocks(options, btype, input, 0, MASTER_BLOCK_SIZE, &mut bw)?;
+    if options.verbose {
+        print_deflate_summary(input.len(), bw.bit_position().div_ceil(8) as usize);
+    }
+    Ok(bw.finish()?)
+}
+
+fn print_deflate_summary(insize: usize, outsize: usize) {
+    eprintln!(
+        "Original Size: {}, Deflate: {}, Compression: {:.2}%",
+        insize, outsize,
+        100.0 * (insize as f64 - outsize as f64) / insize as f64
+    );
+}
+
+pub fn deflate_fixed_block(lz77: &LZ77Store, lstart: usize, lend: usize, final_block: bool) -> Vec<u8> {
+    let mut bw = BitWriter::new();
+    add_lz77_block(&Options::default(), 1, final_block, lz77, lstart, lend, 0, &mut bw);
+    bw.into_bytes()
+}
+
+pub fn deflate_greedy_fixed(input: &[u8]) -> Result<Vec<u8>, ZopfliError> {
     let opts = Options::default();
     let mut state = BlockState::new(&opts, 0, input.len(), true);
     let mut store = LZ77Store::new(input);
     let mut hash = Hash::new(crate::types::WINDOW_SIZE);
     
-    lz77_greedy(&mut state, input, 0, input.len(), &mut store, &mut hash);
-    deflate_fixed_block(&store, 0, store.size(), true)
+    lz77_greedy(&mut state, input, 0, input.len(), &mut store, &mut hash)?;
+    Ok(deflate_fixed_block(&store, 0, store.size(), true))
 }
 
 #[cfg(test)]
@@ -128,20 +960,447 @@ mod tests {
     #[test]
     fn test_bit_writer() {
         let mut bw = BitWriter::new();
-        bw.add_bit(1);
-        bw.add_bit(0);
-        bw.add_bit(1);
+        bw.write_bits(0b101, 3);
+        bw.write_bits(0x1ffff, 17);
+        bw.write_bits(0, 0);
+        bw.write_bits(0xdeadbeef, 32);
+        assert_eq!(bw.bit_position(), 52);
+        bw.align_to_byte();
+        assert_eq!(bw.bit_position(), 56);
+        bw.write_bytes(b"xy");
+        bw.write_bits(1, 1);
+        assert_eq!(bw.bit_position(), 73);
         
-        assert_eq!(bw.out[0] & 0x07, 0b101);
+        let out = bw.into_bytes();
+        let expected = 0b101u64 | (0x1ffff << 3) | (0xdeadbeef << 20);
+        assert_eq!(&out[..7], &expected.to_le_bytes()[..7]);
+        assert_eq!(&out[7..], &[b'x', b'y', 1]);
+    }
+    
+    #[test]
+    fn test_bit_writer_huffman_codes() {
+        // Code 110 is written starting at its most significant bit.
+        let mut symbols = [0b110];
+        reverse_symbols(&[3], 1, &mut symbols);
+        let mut bw = BitWriter::new();
+        bw.write_huffman(symbols[0], 3);
+        assert_eq!(bw.into_bytes(), vec![0b011]);
+    }
+    
+    /// Sink that accepts at most limit bytes in total.
+    struct LimitedSink {
+        written: Vec<u8>,
+        limit: usize,
+        writes: usize,
+    }
+    
+    impl Write for LimitedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.written.len() + buf.len() > self.limit {
+                return Err(io::Error::other("sink full"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn test_bit_writer_sink() {
+        let data: Vec<u8> = (0..200000u32).map(|i| (i * 7 + i / 300) as u8).collect();
+        let sink = LimitedSink { written: Vec::new(), limit: usize::MAX, writes: 0 };
+        let mut bw = BitWriter::with_sink(sink);
+        bw.write_bits(1, 3);
+        bw.align_to_byte();
+        bw.write_bytes(&data);
+        // Most of the output has been passed on before the end.
+        assert!(bw.get_ref().written.len() >= data.len() - SINK_BUFFER_SIZE);
+        assert_eq!(bw.bit_position(), 8 * (data.len() as u64 + 1));
+        bw.write_bits(0b11, 2);
+        let sink = bw.finish().unwrap();
+        assert!(sink.writes > 1);
+        assert_eq!(sink.written.len(), data.len() + 2);
+        assert_eq!(sink.written[0], 1);
+        assert_eq!(&sink.written[1..=data.len()], &data[..]);
+        assert_eq!(sink.written[data.len() + 1], 0b11);
+        
+        // The first error of the sink is returned when finishing.
+        let sink = LimitedSink { written: Vec::new(), limit: 1000, writes: 0 };
+        let mut bw = BitWriter::with_sink(sink);
+        bw.write_bytes(&data);
+        bw.write_bits(1, 1);
+        assert!(bw.finish().is_err());
+    }
+    
+    #[test]
+    fn test_deflate_to_writer() {
+        let data = b"streamed to a writer, streamed to a writer, streamed!".repeat(50);
+        let opts = Options::default();
+        let out = deflate_to(&opts, 2, &data, Vec::new()).unwrap();
+        assert_eq!(out, deflate(&opts, 2, &data).unwrap());
+        let verified = deflate_to(&Options { verify: true, ..opts.clone() }, 2, &data, Vec::new()).unwrap();
+        assert_eq!(verified, out);
+        
+        let sink = LimitedSink { written: Vec::new(), limit: 10, writes: 0 };
+        assert!(matches!(deflate_to(&opts, 2, &data, sink), Err(ZopfliError::Io(_))));
+    }
+    
+    #[test]
+    fn test_master_blocks_stop_after_sink_error() {
+        let data: Vec<u8> = (0..300000u32).map(|i| (i * 7 + i / 300) as u8).collect();
+        let sink = LimitedSink { written: Vec::new(), limit: 0, writes: 0 };
+        let mut bw = BitWriter::with_sink(sink);
+        let result = deflate_master_blocks(&Options::default(), 0, &data, 0, 100000, &mut bw);
+        assert!(matches!(result, Err(ZopfliError::Io(_))));
+        // Only the first master block was compressed.
+        assert!(bw.bit_position() < 8 * 200000);
+        assert_eq!(bw.get_ref().writes, 1);
+    }
+    
+    #[test]
+    fn test_dictionary_master_blocks_stop_after_sink_error() {
+        let data: Vec<u8> = (0..300000u32).map(|i| (i * 7 + i / 300) as u8).collect();
+        let sink = LimitedSink { written: Vec::new(), limit: 0, writes: 0 };
+        let mut bw = BitWriter::with_sink(sink);
+        let result = deflate_master_blocks_with_dictionary(&Options::default(), 0, b"dict", &data, 100000, &mut bw);
+        assert!(matches!(result, Err(ZopfliError::Io(_))));
+        // Only the master block compressed together with the dictionary.
+        assert!(bw.bit_position() < 8 * 200000);
+        assert_eq!(bw.get_ref().writes, 1);
     }
     
     #[test]
     fn test_deflate_simple() {
         let data = b"aaaa";
-        let output = deflate_greedy_fixed(data);
+        let output = deflate_greedy_fixed(data).unwrap();
         
         // Should produce valid DEFLATE output
-        assert!(output.len() > 0);
-        println!("Compressed {} bytes to {} bytes", data.len(), output.len());
+        assert!(!output.is_empty());
+    }
+    
+    /// Number of bits written so far.
+    fn bit_count(bw: &BitWriter) -> usize {
+        bw.bit_position() as usize
+    }
+    
+    fn store_for(data: &[u8]) -> LZ77Store<'_> {
+        use crate::lz77::lz77_greedy;
+        use crate::types::{Hash, WINDOW_SIZE};
+        
+        let opts = Options::default();
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut store = LZ77Store::new(data);
+        let mut hash = Hash::new(WINDOW_SIZE);
+        lz77_greedy(&mut state, data, 0, data.len(), &mut store, &mut hash).unwrap();
+        store
+    }
+    
+    #[test]
+    fn test_encode_tree_size_matches_written_bits() {
+        let data = b"abracadabra, abracadabra, the magic word is abracadabra!";
+        let store = store_for(data);
+        let mut ll_lengths = vec![0u32; NUM_LL];
+        let mut d_lengths = vec![0u32; NUM_D];
+        get_dynamic_lengths(&store, 0, store.size(), &mut ll_lengths, &mut d_lengths);
+        
+        for i in 0..8 {
+            let (use_16, use_17, use_18) = (i & 1 != 0, i & 2 != 0, i & 4 != 0);
+            let mut bw = BitWriter::new();
+            let written = encode_tree(&ll_lengths, &d_lengths, use_16, use_17, use_18, Some(&mut bw));
+            let size_only = encode_tree(&ll_lengths, &d_lengths, use_16, use_17, use_18, None::<&mut BitWriter>);
+            assert_eq!(written, size_only);
+            assert_eq!(bit_count(&bw), written);
+        }
+    }
+    
+    #[test]
+    fn test_dynamic_block_header() {
+        let data = b"hello hello hello dynamic world";
+        let store = store_for(data);
+        let mut bw = BitWriter::new();
+        add_lz77_block(&Options::default(), 2, true, &store, 0, store.size(), data.len(), &mut bw);
+        
+        // BFINAL = 1, BTYPE = 10
+        assert_eq!(bw.into_bytes()[0] & 0x07, 0b101);
+    }
+    
+    #[test]
+    fn test_non_compressed_block_chunking() {
+        // Pseudo-random, incompressible bytes spanning three stored blocks.
+        let mut x: u32 = 1;
+        let data: Vec<u8> = (0..140000).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect();
+        
+        let mut bw = BitWriter::new();
+        add_non_compressed_block(&Options::default(), true, &data, 0, data.len(), &mut bw);
+        let out = bw.into_bytes();
+        
+        // Only the 5 header bytes per 65535-byte chunk are added.
+        assert_eq!(out.len(), data.len() + 3 * 5);
+        
+        let mut pos = 0;
+        let mut decoded = Vec::new();
+        for chunk in 0..3 {
+            let header = out[pos];
+            assert_eq!(header & 1, (chunk == 2) as u8); // BFINAL only on the last one
+            assert_eq!((header >> 1) & 3, 0); // BTYPE 00
+            let len = u16::from_le_bytes([out[pos + 1], out[pos + 2]]) as usize;
+            let nlen = u16::from_le_bytes([out[pos + 3], out[pos + 4]]);
+            assert_eq!(nlen, !(len as u16));
+            assert_eq!(len, if chunk < 2 { 65535 } else { 140000 - 2 * 65535 });
+            decoded.extend_from_slice(&out[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+        }
+        assert_eq!(decoded, data);
+    }
+    
+    #[test]
+    fn test_non_compressed_block_aligns_after_bits() {
+        let data = b"raw";
+        let mut bw = BitWriter::new();
+        bw.write_bits(0b101, 3);
+        add_non_compressed_block(&Options::default(), true, data, 0, data.len(), &mut bw);
+        let out = bw.into_bytes();
+        
+        // Header bits share the first byte, then LEN/NLEN start on a byte boundary.
+        assert_eq!(out[0], 0b101 | (1 << 3));
+        assert_eq!(&out[1..5], &[3, 0, 0xfc, 0xff]);
+        assert_eq!(&out[5..], data);
+    }
+    
+    #[test]
+    fn test_stored_lz77_block() {
+        let data = b"stored through the lz77 store";
+        let store = store_for(data);
+        let mut bw = BitWriter::new();
+        add_lz77_block(&Options::default(), 0, true, &store, 0, store.size(), 0, &mut bw);
+        let out = bw.into_bytes();
+        assert_eq!(out.len(), data.len() + 5);
+        assert_eq!(&out[5..], data);
+    }
+    
+    fn auto_type_block(data: &[u8]) -> Vec<u8> {
+        let store = store_for(data);
+        let mut bw = BitWriter::new();
+        add_lz77_block_auto_type(&Options::default(), true, &store, 0, store.size(), data.len(), &mut bw).unwrap();
+        bw.into_bytes()
+    }
+    
+    #[test]
+    fn test_auto_type_empty_block() {
+        let store = LZ77Store::new(b"");
+        let mut bw = BitWriter::new();
+        add_lz77_block_auto_type(&Options::default(), true, &store, 0, 0, 0, &mut bw).unwrap();
+        assert_eq!(bit_count(&bw), 10);
+        assert_eq!(bw.into_bytes(), vec![0b011, 0]);
+    }
+    
+    #[test]
+    fn test_auto_type_invalid_range() {
+        let store = store_for(b"abc");
+        let mut bw = BitWriter::new();
+        let result = add_lz77_block_auto_type(&Options::default(), true, &store, 0, 5, 0, &mut bw);
+        assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+        let result = add_lz77_block_auto_type(&Options::default(), true, &store, 2, 1, 0, &mut bw);
+        assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+        assert_eq!(bit_count(&bw), 0);
+    }
+    
+    #[test]
+    fn test_auto_type_picks_fixed_for_tiny_input() {
+        let out = auto_type_block(b"tiny");
+        assert_eq!((out[0] >> 1) & 3, 1);
+    }
+    
+    #[test]
+    fn test_auto_type_picks_stored_for_incompressible_input() {
+        let mut x: u32 = 7;
+        let data: Vec<u8> = (0..4096).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect();
+        let out = auto_type_block(&data);
+        assert_eq!((out[0] >> 1) & 3, 0);
+        assert_eq!(out.len(), data.len() + 5);
+    }
+    
+    #[test]
+    fn test_auto_type_picks_dynamic_for_text() {
+        let mut data = Vec::new();
+        for i in 0..300 {
+            data.extend_from_slice(format!("line {} of some skewed text eeeee\n", i % 17).as_bytes());
+        }
+        let out = auto_type_block(&data);
+        assert_eq!((out[0] >> 1) & 3, 2);
+    }
+    
+    #[test]
+    fn test_deflate_empty_input() {
+        let opts = Options::default();
+        for btype in 0..=2 {
+            let out = deflate(&opts, btype, b"").unwrap();
+            assert!(!out.is_empty());
+            assert_eq!(out[0] & 1, 1); // BFINAL
+        }
+    }
+    
+    #[test]
+    fn test_deflate_all_block_types() {
+        let opts = Options::default();
+        let data = b"deflate deflate deflate with every block type, deflate!";
+        let stored = deflate(&opts, 0, data).unwrap();
+        let fixed = deflate(&opts, 1, data).unwrap();
+        let best = deflate(&opts, 2, data).unwrap();
+        
+        assert_eq!(stored.len(), data.len() + 5);
+        assert_eq!((fixed[0] >> 1) & 3, 1);
+        assert!(best.len() <= fixed.len());
+        assert!(best.len() < stored.len());
+    }
+    
+    #[test]
+    fn test_deflate_master_blocks() {
+        // Stored blocks restart their 65535-byte chunking at each master block.
+        let data = vec![b'x'; MASTER_BLOCK_SIZE + 10];
+        let out = deflate(&Options::default(), 0, &data).unwrap();
+        let chunks = MASTER_BLOCK_SIZE.div_ceil(65535) + 1;
+        assert_eq!(out.len(), data.len() + chunks * 5);
+        
+        // The last block of the first master block is not final.
+        let last_header = out.len() - 10 - 5;
+        assert_eq!(out[last_header] & 1, 1);
+        let prev_header = last_header - (MASTER_BLOCK_SIZE % 65535) - 5;
+        assert_eq!(out[prev_header] & 1, 0);
+    }
+
+    #[test]
+    fn test_dictionary_master_blocks_match_combined_input() {
+        let opts = Options { greedy: true, blocksplitting: false, ..Options::default() };
+        let dictionary = b"dictionary words and phrases ".repeat(200);
+        let dictionary = &dictionary[dictionary.len() - WINDOW_SIZE.min(dictionary.len())..];
+        let mut data = Vec::new();
+        let mut x: u32 = 3;
+        while data.len() < 2 * WINDOW_SIZE + 100 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.extend_from_slice(dictionary.chunks(7).nth((x >> 16) as usize % 100).unwrap());
+        }
+        let mut combined = dictionary.to_vec();
+        combined.extend_from_slice(&data);
+
+        for size in [WINDOW_SIZE, data.len()] {
+            let mut expected = BitWriter::new();
+            deflate_master_blocks(&opts, 2, &combined, dictionary.len(), size, &mut expected).unwrap();
+            let mut bw = BitWriter::new();
+            deflate_master_blocks_with_dictionary(&opts, 2, dictionary, &data, size, &mut bw).unwrap();
+            assert_eq!(bw.into_bytes(), expected.into_bytes(), "master block size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_greedy_options() {
+        let data = b"greedy greedy greedy parse of some greedy text".repeat(20);
+        let fast = deflate(&Options::fast(), 2, &data).unwrap();
+        assert_eq!(crate::inflate::inflate(&fast).unwrap(), data);
+        assert!(fast.len() < data.len() / 4);
+    }
+
+    #[test]
+    fn test_binary_tree_match_finder() {
+        let mut data = Vec::new();
+        let mut x: u32 = 9;
+        for _ in 0..20000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push(b'a' + ((x >> 16) & 1) as u8);
+        }
+        let opts = Options {
+            numiterations: 2,
+            match_finder: crate::matchfinder::MatchFinderKind::BinaryTree,
+            ..Options::default()
+        };
+        let out = deflate(&opts, 2, &data).unwrap();
+        assert_eq!(crate::inflate::inflate(&out).unwrap(), data);
+        assert!(out.len() < data.len() / 6);
+    }
+
+    #[test]
+    fn test_threads_match_single_threaded() {
+        // Alternate text and incompressible master blocks, so that stored blocks
+        // start at arbitrary bit positions.
+        let mut data = Vec::new();
+        let mut x: u32 = 3;
+        for i in 0..5 {
+            for _ in 0..2000 {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                data.push(if i % 2 == 0 { b"abc "[(x >> 16) as usize % 4] } else { (x >> 16) as u8 });
+            }
+        }
+
+        let single = Options { numiterations: 2, ..Options::default() };
+        let mut expected = BitWriter::new();
+        deflate_master_blocks(&single, 2, &data, 0, 2000, &mut expected).unwrap();
+        let expected = expected.into_bytes();
+
+        for threads in [2, 3, 8] {
+            let opts = Options { threads, ..single.clone() };
+            let mut bw = BitWriter::new();
+            deflate_master_blocks(&opts, 2, &data, 0, 2000, &mut bw).unwrap();
+            assert_eq!(bw.into_bytes(), expected, "threads {}", threads);
+        }
+        assert_eq!(crate::inflate::inflate(&expected).unwrap(), data);
+    }
+
+    #[test]
+    fn test_parallel_subblocks_match_single_threaded() {
+        // Four sections with different alphabets, so the master block is split.
+        let mut data = Vec::new();
+        let mut x: u32 = 5;
+        for alphabet in [b"abcd", b"wxyz", b"0123", b"abcd"] {
+            for _ in 0..1000 {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                data.push(alphabet[(x >> 16) as usize % 4]);
+            }
+        }
+
+        let single = Options { numiterations: 1, ..Options::default() };
+        assert!(crate::split::block_split(&single, &data, 0, data.len(), 15).unwrap().len() > 1);
+        let expected = deflate(&single, 2, &data).unwrap();
+        for threads in [3, 16] {
+            let opts = Options { threads, ..single.clone() };
+            assert_eq!(deflate(&opts, 2, &data).unwrap(), expected, "threads {}", threads);
+        }
+        assert_eq!(crate::inflate::inflate(&expected).unwrap(), data);
+    }
+
+    use proptest::prelude::*;
+    
+    /// Histogram with many zero runs, so that every RLE symbol gets exercised.
+    fn sparse_counts(n: usize) -> impl Strategy<Value = Vec<usize>> {
+        prop::collection::vec(prop_oneof![3 => Just(0usize), 1 => 1usize..1000], n)
+    }
+    
+    proptest! {
+        #[test]
+        fn test_calculate_tree_size_matches_written_tree(
+            ll_counts in sparse_counts(NUM_LL),
+            d_counts in sparse_counts(NUM_D),
+        ) {
+            let mut ll_counts = ll_counts;
+            ll_counts[256] = 1; // End symbol.
+            let mut ll_lengths = vec![0u32; NUM_LL];
+            let mut d_lengths = vec![0u32; NUM_D];
+            calculate_bit_lengths(&ll_counts, NUM_LL, 15, &mut ll_lengths);
+            calculate_bit_lengths(&d_counts, NUM_D, 15, &mut d_lengths);
+            crate::block::patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+            
+            let mut bw = BitWriter::new();
+            add_dynamic_tree(&ll_lengths, &d_lengths, &mut bw);
+            prop_assert_eq!(crate::block::calculate_tree_size(&ll_lengths, &d_lengths), bit_count(&bw));
+        }
     }
 }
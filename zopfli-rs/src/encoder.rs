@@ -0,0 +1,642 @@
+// Copyright Anysphere Inc.
+// Streaming compressors implementing std::io::Write
+
+use std::io::{self, Write};
+
+use crate::types::{Options, MASTER_BLOCK_SIZE, WINDOW_SIZE};
+use crate::deflate::{deflate_part, BitWriter};
+use crate::error::{check_options, ZopfliError};
+use crate::gzip::{update_crc, write_gzip_header, write_gzip_trailer, GzipHeader};
+use crate::zlib::{adler32, update_adler32, write_zlib_header, write_zlib_trailer};
+use crate::verify::{MasterBlockVerifier, VerifyError};
+
+/// Container trailer written after the deflate stream, with the checksum of the
+/// input pushed so far.
+#[derive(Clone)]
+pub(crate) enum Trailer {
+    /// Raw deflate stream, without a trailer.
+    None,
+    /// gzip CRC-32 and size.
+    Gzip { crc: u32, insize: u32 },
+    /// zlib Adler-32.
+    Zlib { adler: u32 },
+}
+
+impl Trailer {
+    /// Adds data to the checksum and, for gzip, to the size modulo 2^32.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Trailer::None => {}
+            Trailer::Gzip { crc, insize } => {
+                *crc = update_crc(*crc, data);
+                *insize = insize.wrapping_add(data.len() as u32);
+            }
+            Trailer::Zlib { adler } => *adler = update_adler32(*adler, data),
+        }
+    }
+
+    /// Returns the bytes of the trailer.
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            Trailer::None => {}
+            Trailer::Gzip { crc, insize } => write_gzip_trailer(crc, insize, &mut out),
+            Trailer::Zlib { adler } => write_zlib_trailer(adler, &mut out),
+        }
+        out
+    }
+}
+
+/// Compresses input that arrives in pieces one master block at a time, carrying
+/// the last WINDOW_SIZE bytes of each master block over as dictionary for the
+/// next one. Shared by the Write encoders and the Read adapters.
+pub(crate) struct MasterBlockCompressor {
+    options: Options,
+    /// The window carried over from the previous master block, followed by the
+    /// input not compressed yet.
+    buffer: Vec<u8>,
+    /// Size of the window at the start of buffer.
+    window: usize,
+    /// Compressed output, of which the first outstart bytes have already been
+    /// handed out.
+    bw: BitWriter,
+    outstart: usize,
+    /// Checksum of all input pushed, written after the final block.
+    trailer: Trailer,
+    /// Decodes each master block before its output is handed out, if
+    /// options.verify is set.
+    verifier: Option<MasterBlockVerifier>,
+    /// The failed verification. No more output is produced after it.
+    failed: Option<VerifyError>,
+    /// Whether the final block has been compressed.
+    finished: bool,
+    pub(crate) master_block_size: usize,
+}
+
+impl MasterBlockCompressor {
+    /// Creates a compressor whose output starts with prefix, a container header,
+    /// ends with trailer, and whose matches may refer back into the preset
+    /// dictionary.
+    pub(crate) fn new(
+        options: &Options,
+        dictionary: &[u8],
+        prefix: Vec<u8>,
+        trailer: Trailer,
+    ) -> Result<Self, ZopfliError> {
+        check_options(options, 2)?;
+        let dictionary = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..];
+        Ok(MasterBlockCompressor {
+            options: options.clone(),
+            buffer: dictionary.to_vec(),
+            window: dictionary.len(),
+            bw: BitWriter::with_sink(prefix),
+            outstart: 0,
+            verifier: options.verify.then(|| MasterBlockVerifier::new(trailer.clone())),
+            trailer,
+            failed: None,
+            finished: false,
+            master_block_size: MASTER_BLOCK_SIZE,
+        })
+    }
+
+    /// Returns the amount of buffered input not compressed yet.
+    pub(crate) fn pending(&self) -> usize {
+        self.buffer.len() - self.window
+    }
+
+    /// Appends input to be compressed, and adds it to the trailer checksum.
+    /// Returns InputTooLarge, without taking any of the input, if it can not
+    /// be buffered.
+    pub(crate) fn push(&mut self, data: &[u8]) -> Result<(), ZopfliError> {
+        self.buffer.try_reserve(data.len()).map_err(|_| ZopfliError::InputTooLarge)?;
+        self.trailer.update(data);
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Compresses a full master block if more input follows it, since the last
+    /// one must have the final bit set. Returns whether a block was compressed.
+    pub(crate) fn compress_full_block(&mut self) -> Result<bool, ZopfliError> {
+        if self.pending() <= self.master_block_size {
+            return Ok(false);
+        }
+        self.compress(self.window + self.master_block_size, false)?;
+        Ok(true)
+    }
+
+    /// Compresses all pending input as the final master block, followed by the
+    /// trailer.
+    pub(crate) fn compress_final_block(&mut self) -> Result<(), ZopfliError> {
+        self.compress(self.buffer.len(), true)?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Returns whether the final block has been compressed.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Compresses buffer[window..inend] as one master block, and verifies it if
+    /// options.verify is set.
+    fn compress(&mut self, inend: usize, final_block: bool) -> Result<(), ZopfliError> {
+        if let Some(e) = &self.failed {
+            return Err(ZopfliError::VerificationFailed(e.clone()));
+        }
+        // Bit offset of the master block in the output buffer.
+        let start = self.bw.get_ref().len() * 8 + self.bw.partial_byte().1 as usize;
+        deflate_part(&self.options, 2, final_block, &self.buffer, self.window, inend, &mut self.bw)?;
+        if final_block {
+            self.bw.align_to_byte();
+            self.bw.write_bytes(&self.trailer.bytes());
+        }
+        self.bw.flush_bytes();
+
+        if let Some(verifier) = &mut self.verifier {
+            let mut compressed = self.bw.get_ref()[start / 8..].to_vec();
+            let (byte, nbits) = self.bw.partial_byte();
+            if nbits > 0 {
+                compressed.push(byte);
+            }
+            let (window, input) = self.buffer[..inend].split_at(self.window);
+            if let Err(e) = verifier.verify(window, &compressed, start % 8, input, final_block) {
+                // None of the master block may be handed out.
+                self.bw.get_mut().truncate(start / 8);
+                self.failed = Some(e.clone());
+                return Err(e.into());
+            }
+        }
+
+        // Keep the last WINDOW_SIZE bytes as dictionary for the next master block.
+        let windowstart = inend.saturating_sub(WINDOW_SIZE);
+        self.buffer.drain(..windowstart);
+        self.window = inend - windowstart;
+        Ok(())
+    }
+
+    /// Returns the compressed bytes that are complete. After the final block
+    /// this is all of them, otherwise the bits of a partial last byte are kept
+    /// back in the bit writer for the next block.
+    pub(crate) fn output(&self) -> &[u8] {
+        &self.bw.get_ref()[self.outstart..]
+    }
+
+    /// Marks the first n bytes of output as handed out. The space is reclaimed
+    /// once all of it is, or once it is more than half of the buffer, so that
+    /// handing out output in small pieces takes linear time.
+    pub(crate) fn consume(&mut self, n: usize) {
+        self.outstart += n;
+        let out = self.bw.get_mut();
+        debug_assert!(self.outstart <= out.len());
+        if self.outstart == out.len() {
+            out.clear();
+            self.outstart = 0;
+        } else if self.outstart > out.len() / 2 {
+            out.drain(..self.outstart);
+            self.outstart = 0;
+        }
+    }
+}
+
+/// Compresses the data written to it into a raw deflate stream on the inner
+/// writer. Input is buffered until a full master block of MASTER_BLOCK_SIZE
+/// bytes is available, which is then compressed with the preceding WINDOW_SIZE
+/// bytes as dictionary, exactly like deflate does for in-memory input. Master
+/// blocks are compressed one after the other, see Options::threads.
+///
+/// finish must be called to compress the last master block and end the stream;
+/// dropping the encoder without it loses the buffered data.
+///
+/// write always takes all of its input once it has started compressing it. If
+/// the inner writer then fails, the error is returned by the next call to
+/// write, flush or finish, and the output not written yet is kept so that a
+/// retry continues where the inner writer stopped.
+///
+/// Errors other than those of the inner writer are returned by write and flush
+/// as an io::Error wrapping the ZopfliError, which io::Error::get_ref can
+/// downcast; finish returns the ZopfliError itself.
+pub struct DeflateEncoder<W: Write> {
+    inner: W,
+    compressor: MasterBlockCompressor,
+    /// Error not reported yet.
+    error: Option<ZopfliError>,
+}
+
+impl<W: Write> DeflateEncoder<W> {
+    /// Creates an encoder writing the compressed stream to inner.
+    pub fn new(options: &Options, inner: W) -> Result<Self, ZopfliError> {
+        Self::with_prefix(options, inner, &[], Vec::new(), Trailer::None)
+    }
+
+    /// Creates an encoder whose matches may refer back into the preset
+    /// dictionary. The decoder must be given the same dictionary.
+    pub fn with_dictionary(options: &Options, inner: W, dictionary: &[u8]) -> Result<Self, ZopfliError> {
+        Self::with_prefix(options, inner, dictionary, Vec::new(), Trailer::None)
+    }
+
+    /// Creates an encoder that writes prefix, a container header, before the
+    /// deflate stream and trailer after it.
+    fn with_prefix(
+        options: &Options,
+        inner: W,
+        dictionary: &[u8],
+        prefix: Vec<u8>,
+        trailer: Trailer,
+    ) -> Result<Self, ZopfliError> {
+        Ok(DeflateEncoder {
+            inner,
+            compressor: MasterBlockCompressor::new(options, dictionary, prefix, trailer)?,
+            error: None,
+        })
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Writes all completed output bytes to the inner writer. Only the bytes
+    /// the inner writer accepted are removed from the output, so nothing is
+    /// written twice after an error.
+    fn write_output(&mut self) -> io::Result<()> {
+        while !self.compressor.output().is_empty() {
+            match self.inner.write(self.compressor.output()) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.compressor.consume(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the error not reported yet, then writes the pending output and
+    /// compresses and writes all full master blocks.
+    fn write_full_blocks(&mut self) -> Result<(), ZopfliError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.write_output()?;
+        while self.compressor.compress_full_block()? {
+            self.write_output()?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the remaining input as the final master block, and returns
+    /// the inner writer.
+    pub fn finish(mut self) -> Result<W, ZopfliError> {
+        self.write_full_blocks()?;
+        self.compressor.compress_final_block()?;
+        self.write_output()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for DeflateEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Errors from before are returned before taking any input.
+        self.write_full_blocks()?;
+        self.compressor.push(buf)?;
+        if let Err(e) = self.write_full_blocks() {
+            self.error = Some(e);
+        }
+        Ok(buf.len())
+    }
+
+    /// Writes the completed output and flushes the inner writer. Input of the
+    /// current master block stays buffered, since deflate blocks can only be
+    /// written once they are complete.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_full_blocks()?;
+        self.inner.flush()
+    }
+}
+
+/// Compresses the data written to it in the gzip format, RFC 1952. See
+/// DeflateEncoder for the buffering behavior and errors.
+pub struct GzipEncoder<W: Write> {
+    deflate: DeflateEncoder<W>,
+}
+
+impl<W: Write> GzipEncoder<W> {
+    /// Creates an encoder writing a gzip member with a default header to inner.
+    pub fn new(options: &Options, inner: W) -> Result<Self, ZopfliError> {
+        Self::with_header(options, &GzipHeader::default(), inner)
+    }
+
+    /// Creates an encoder writing a gzip member with the given header to inner.
+    pub fn with_header(options: &Options, header: &GzipHeader, inner: W) -> Result<Self, ZopfliError> {
+        let mut prefix = Vec::new();
+        write_gzip_header(header, &mut prefix)?;
+        let trailer = Trailer::Gzip { crc: 0, insize: 0 };
+        Ok(GzipEncoder { deflate: DeflateEncoder::with_prefix(options, inner, &[], prefix, trailer)? })
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        self.deflate.get_ref()
+    }
+
+    /// Ends the deflate stream, writes the trailer and returns the inner writer.
+    pub fn finish(self) -> Result<W, ZopfliError> {
+        self.deflate.finish()
+    }
+}
+
+impl<W: Write> Write for GzipEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deflate.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate.flush()
+    }
+}
+
+/// Compresses the data written to it in the zlib format, RFC 1950. See
+/// DeflateEncoder for the buffering behavior and errors.
+pub struct ZlibEncoder<W: Write> {
+    deflate: DeflateEncoder<W>,
+}
+
+impl<W: Write> ZlibEncoder<W> {
+    /// Creates an encoder writing a zlib stream to inner.
+    pub fn new(options: &Options, inner: W) -> Result<Self, ZopfliError> {
+        let mut prefix = Vec::new();
+        write_zlib_header(None, &mut prefix);
+        let trailer = Trailer::Zlib { adler: 1 };
+        Ok(ZlibEncoder { deflate: DeflateEncoder::with_prefix(options, inner, &[], prefix, trailer)? })
+    }
+
+    /// Creates an encoder writing a zlib stream that uses the preset dictionary.
+    /// The decompressor must be given the same dictionary.
+    pub fn with_dictionary(options: &Options, inner: W, dictionary: &[u8]) -> Result<Self, ZopfliError> {
+        let mut prefix = Vec::new();
+        write_zlib_header(Some(adler32(dictionary)), &mut prefix);
+        let trailer = Trailer::Zlib { adler: 1 };
+        Ok(ZlibEncoder { deflate: DeflateEncoder::with_prefix(options, inner, dictionary, prefix, trailer)? })
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        self.deflate.get_ref()
+    }
+
+    /// Ends the deflate stream, writes the trailer and returns the inner writer.
+    pub fn finish(self) -> Result<W, ZopfliError> {
+        self.deflate.finish()
+    }
+}
+
+impl<W: Write> Write for ZlibEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deflate.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::deflate;
+    use crate::gzip::gzip_compress;
+    use crate::inflate::{inflate, inflate_with_dictionary};
+    use crate::zlib::{zlib_compress, zlib_compress_with_dictionary};
+
+    fn test_data(size: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(size);
+        let mut x: u32 = 1;
+        while data.len() < size {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.extend_from_slice(["alpha ", "beta ", "gamma ", "delta "][(x >> 16) as usize % 4].as_bytes());
+        }
+        data.truncate(size);
+        data
+    }
+
+    fn fast_options() -> Options {
+        Options { numiterations: 1, ..Options::default() }
+    }
+
+    #[test]
+    fn test_single_master_block_matches_in_memory() {
+        let opts = fast_options();
+        let data = test_data(5000);
+
+        let mut enc = DeflateEncoder::new(&opts, Vec::new()).unwrap();
+        for chunk in data.chunks(333) {
+            enc.write_all(chunk).unwrap();
+        }
+        assert_eq!(enc.finish().unwrap(), deflate(&opts, 2, &data).unwrap());
+
+        let mut enc = GzipEncoder::new(&opts, Vec::new()).unwrap();
+        enc.write_all(&data).unwrap();
+        assert_eq!(enc.finish().unwrap(), gzip_compress(&opts, &data).unwrap());
+
+        let mut enc = ZlibEncoder::new(&opts, Vec::new()).unwrap();
+        enc.write_all(&data).unwrap();
+        assert_eq!(enc.finish().unwrap(), zlib_compress(&opts, &data).unwrap());
+
+        let dictionary = b"alpha beta gamma delta";
+        let mut enc = ZlibEncoder::with_dictionary(&opts, Vec::new(), dictionary).unwrap();
+        enc.write_all(&data).unwrap();
+        assert_eq!(enc.finish().unwrap(), zlib_compress_with_dictionary(&opts, Some(dictionary), &data).unwrap());
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let opts = fast_options();
+        let enc = DeflateEncoder::new(&opts, Vec::new()).unwrap();
+        assert_eq!(enc.finish().unwrap(), deflate(&opts, 2, &[]).unwrap());
+    }
+
+    #[test]
+    fn test_multiple_master_blocks() {
+        let opts = fast_options();
+        let data = test_data(20000);
+
+        let mut enc = DeflateEncoder::new(&opts, Vec::new()).unwrap();
+        enc.compressor.master_block_size = 6000;
+        for chunk in data.chunks(2500) {
+            enc.write_all(chunk).unwrap();
+        }
+        // Three master blocks were compressed, the last one is still buffered.
+        assert!(!enc.get_ref().is_empty());
+        assert_eq!(enc.compressor.pending(), 2000);
+        let out = enc.finish().unwrap();
+        assert_eq!(inflate(&out).unwrap(), data);
+
+        // Same output as compressing the master blocks in memory.
+        let mut bw = BitWriter::new();
+        for start in (0..data.len()).step_by(6000) {
+            let end = (start + 6000).min(data.len());
+            deflate_part(&opts, 2, end == data.len(), &data, start, end, &mut bw).unwrap();
+        }
+        assert_eq!(out, bw.into_bytes());
+    }
+
+    #[test]
+    fn test_dictionary_window_is_carried() {
+        let opts = fast_options();
+        let dictionary = test_data(40000);
+        let data = test_data(12000);
+
+        let mut enc = DeflateEncoder::with_dictionary(&opts, Vec::new(), &dictionary).unwrap();
+        enc.compressor.master_block_size = 5000;
+        enc.write_all(&data).unwrap();
+        let out = enc.finish().unwrap();
+        assert_eq!(inflate_with_dictionary(&dictionary, &out).unwrap(), data);
+    }
+
+    #[test]
+    fn test_gzip_trailer_over_master_blocks() {
+        let opts = fast_options();
+        let data = test_data(12000);
+
+        let mut enc = GzipEncoder::new(&opts, Vec::new()).unwrap();
+        enc.deflate.compressor.master_block_size = 5000;
+        enc.write_all(&data).unwrap();
+        let out = enc.finish().unwrap();
+        let n = out.len();
+        assert_eq!(&out[n - 8..n - 4], &crate::gzip::crc32(&data).to_le_bytes());
+        assert_eq!(&out[n - 4..], &(data.len() as u32).to_le_bytes());
+        assert_eq!(inflate(&out[10..n - 8]).unwrap(), data);
+    }
+
+    #[test]
+    fn test_verify_master_blocks() {
+        let data = test_data(12000);
+        let dictionary = test_data(3000);
+        let verified = Options { verify: true, ..fast_options() };
+
+        let outputs: Vec<Vec<u8>> = [fast_options(), verified.clone()]
+            .iter()
+            .map(|opts| {
+                let mut enc = ZlibEncoder::with_dictionary(opts, Vec::new(), &dictionary).unwrap();
+                enc.deflate.compressor.master_block_size = 5000;
+                enc.write_all(&data).unwrap();
+                enc.finish().unwrap()
+            })
+            .collect();
+        assert_eq!(outputs[0], outputs[1]);
+
+        // A wrong trailer is caught, and nothing of the final master block is
+        // handed out.
+        let mut enc = GzipEncoder::new(&verified, Vec::new()).unwrap();
+        enc.deflate.compressor.master_block_size = 5000;
+        enc.write_all(&data).unwrap();
+        let compressor = &mut enc.deflate.compressor;
+        compressor.trailer = Trailer::Gzip { crc: 1, insize: data.len() as u32 };
+        let before = compressor.output().len();
+        let result = compressor.compress_final_block();
+        assert!(matches!(result, Err(ZopfliError::VerificationFailed(VerifyError::Trailer))));
+        assert_eq!(compressor.output().len(), before);
+        assert!(!compressor.is_finished());
+        assert!(enc.finish().is_err());
+    }
+
+    /// Sink that accepts at most 10 bytes per write, and fails every third of
+    /// its first 30 writes, alternating between interrupted and other errors.
+    struct FlakySink {
+        out: Vec<u8>,
+        calls: usize,
+    }
+
+    impl Write for FlakySink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            match self.calls % 6 {
+                0 if self.calls <= 30 => Err(io::ErrorKind::Interrupted.into()),
+                3 if self.calls <= 30 => Err(io::Error::other("flaky")),
+                _ => {
+                    let n = buf.len().min(10);
+                    self.out.extend_from_slice(&buf[..n]);
+                    Ok(n)
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_consume_in_pieces() {
+        let prefix: Vec<u8> = (0..100).collect();
+        let mut compressor = MasterBlockCompressor::new(&fast_options(), &[], prefix.clone(), Trailer::None).unwrap();
+        let mut consumed = 0;
+        while consumed < prefix.len() {
+            assert_eq!(compressor.output(), &prefix[consumed..]);
+            let n = compressor.output().len().min(7);
+            compressor.consume(n);
+            consumed += n;
+        }
+        assert!(compressor.output().is_empty());
+
+        // Output compressed after consuming part of the buffer follows on.
+        compressor.push(b"abc").unwrap();
+        compressor.compress_final_block().unwrap();
+        assert_eq!(inflate(compressor.output()).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_failing_and_partial_writes() {
+        let opts = fast_options();
+        let data = test_data(20000);
+
+        let mut enc = GzipEncoder::new(&opts, FlakySink { out: Vec::new(), calls: 0 }).unwrap();
+        enc.deflate.compressor.master_block_size = 3000;
+        let mut errors = 0;
+        for chunk in data.chunks(1000) {
+            // Input is either refused with an error or taken completely, so it
+            // can be retried as is.
+            loop {
+                match enc.write(chunk) {
+                    Ok(n) => {
+                        assert_eq!(n, chunk.len());
+                        break;
+                    }
+                    Err(_) => errors += 1,
+                }
+            }
+        }
+        assert!(errors > 0);
+        assert!(enc.get_ref().calls > 30);
+        let out = enc.finish().unwrap().out;
+        let mut expected = GzipEncoder::new(&opts, Vec::new()).unwrap();
+        expected.deflate.compressor.master_block_size = 3000;
+        expected.write_all(&data).unwrap();
+        assert_eq!(out, expected.finish().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let opts = Options { numiterations: 0, ..Options::default() };
+        assert!(matches!(DeflateEncoder::new(&opts, Vec::new()), Err(ZopfliError::InvalidOptions(_))));
+        let header = GzipHeader { filename: Some(b"a\0".to_vec()), ..GzipHeader::default() };
+        assert!(matches!(
+            GzipEncoder::with_header(&fast_options(), &header, Vec::new()),
+            Err(ZopfliError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_gzip_size_wraps() {
+        // ISIZE is the input size modulo 2^32, RFC 1952.
+        let trailer = Trailer::Gzip { crc: 0, insize: u32::MAX - 1 };
+        let prefix = Vec::new();
+        let compressor = MasterBlockCompressor::new(&fast_options(), &[], prefix, trailer).unwrap();
+        let mut encoder = DeflateEncoder { inner: Vec::new(), compressor, error: None };
+        encoder.write_all(b"abcd").unwrap();
+
+        let out = encoder.finish().unwrap();
+        assert_eq!(inflate(&out[..out.len() - 8]).unwrap(), b"abcd");
+        assert_eq!(&out[out.len() - 4..], &2u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright Anysphere Inc.
+// Error type returned by the public compression functions
+
+use std::fmt;
+use std::io;
+
+use crate::types::Options;
+use crate::verify::VerifyError;
+
+/// Errors returned by the public compression functions.
+#[derive(Debug)]
+pub enum ZopfliError {
+    /// The options or arguments can not be used for compression.
+    InvalidOptions(&'static str),
+    /// A gzip header field can not be encoded.
+    InvalidHeader(&'static str),
+    /// The input, together with any preset dictionary, is too large to be
+    /// buffered in memory.
+    InputTooLarge,
+    /// Reading the input or writing the output failed.
+    Io(io::Error),
+    /// Decoding the output did not reproduce the input, see Options::verify.
+    VerificationFailed(VerifyError),
+    /// One of the compression threads of Options::threads panicked.
+    ThreadPanicked,
+}
+
+impl fmt::Display for ZopfliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZopfliError::InvalidOptions(msg) => write!(f, "invalid options: {}", msg),
+            ZopfliError::InvalidHeader(msg) => write!(f, "invalid gzip header: {}", msg),
+            ZopfliError::InputTooLarge => f.write_str("input too large"),
+            ZopfliError::Io(e) => write!(f, "I/O error: {}", e),
+            ZopfliError::VerificationFailed(e) => e.fmt(f),
+            ZopfliError::ThreadPanicked => f.write_str("compression thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for ZopfliError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZopfliError::Io(e) => Some(e),
+            ZopfliError::VerificationFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ZopfliError {
+    fn from(e: io::Error) -> Self {
+        ZopfliError::Io(e)
+    }
+}
+
+impl From<VerifyError> for ZopfliError {
+    fn from(e: VerifyError) -> Self {
+        ZopfliError::VerificationFailed(e)
+    }
+}
+
+impl From<ZopfliError> for io::Error {
+    fn from(e: ZopfliError) -> Self {
+        match e {
+            ZopfliError::Io(e) => e,
+            ZopfliError::InvalidOptions(_) | ZopfliError::InvalidHeader(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, e)
+            }
+            _ => io::Error::other(e),
+        }
+    }
+}
+
+/// Checks that the options can be used for compression with the given block
+/// type.
+pub fn check_options(options: &Options, btype: i32) -> Result<(), ZopfliError> {
+    if !(0..=2).contains(&btype) {
+        return Err(ZopfliError::InvalidOptions("block type must be 0, 1 or 2"));
+    }
+    options.validate()
+}
+
+/// Checks that instart..inend is a range of input.
+pub(crate) fn check_range(input: &[u8], instart: usize, inend: usize) -> Result<(), ZopfliError> {
+    if instart > inend || inend > input.len() {
+        return Err(ZopfliError::InvalidOptions("range is out of bounds of the input"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_options() {
+        assert!(check_options(&Options::default(), 2).is_ok());
+        assert!(matches!(check_options(&Options::default(), 3), Err(ZopfliError::InvalidOptions(_))));
+        let opts = Options { numiterations: 0, ..Options::default() };
+        assert!(matches!(check_options(&opts, 2), Err(ZopfliError::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn test_io_error_conversion() {
+        let e: io::Error = ZopfliError::InvalidOptions("bad").into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e: io::Error = ZopfliError::Io(io::Error::from(io::ErrorKind::BrokenPipe)).into();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright Anysphere Inc.
+// Output format selection
+
+use crate::types::Options;
+use crate::deflate::deflate;
+use crate::gzip::gzip_compress;
+use crate::zlib::zlib_compress;
+use crate::error::ZopfliError;
+
+/// Container format of the compressed output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// gzip, RFC 1952.
+    #[default]
+    Gzip,
+    /// zlib, RFC 1950.
+    Zlib,
+    /// Raw deflate, RFC 1951.
+    Deflate,
+}
+
+impl Format {
+    /// Returns the file name extension used for this format, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Gzip => "gz",
+            Format::Zlib => "zlib",
+            Format::Deflate => "deflate",
+        }
+    }
+}
+
+/// Compresses the data in the given output format, using dynamic blocks for
+/// deflate.
+pub fn compress(options: &Options, format: Format, input: &[u8]) -> Result<Vec<u8>, ZopfliError> {
+    match format {
+        Format::Gzip => gzip_compress(options, input),
+        Format::Zlib => zlib_compress(options, input),
+        Format::Deflate => deflate(options, 2, input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_dispatches_on_format() {
+        let opts = Options::default();
+        let data = b"format format format";
+        assert_eq!(compress(&opts, Format::Gzip, data).unwrap(), gzip_compress(&opts, data).unwrap());
+        assert_eq!(compress(&opts, Format::Zlib, data).unwrap(), zlib_compress(&opts, data).unwrap());
+        assert_eq!(compress(&opts, Format::Deflate, data).unwrap(), deflate(&opts, 2, data).unwrap());
+    }
+
+    #[test]
+    fn test_compress_invalid_options() {
+        let opts = Options { numiterations: 0, ..Options::default() };
+        for format in [Format::Gzip, Format::Zlib, Format::Deflate] {
+            assert!(matches!(compress(&opts, format, b"abc"), Err(ZopfliError::InvalidOptions(_))));
+        }
+    }
+
+    #[test]
+    fn test_compress_verify() {
+        let opts = Options { verify: true, ..Options::default() };
+        let data = b"verified verified verified".repeat(50);
+        for format in [Format::Gzip, Format::Zlib, Format::Deflate] {
+            let out = compress(&opts, format, &data).unwrap();
+            assert_eq!(out, compress(&Options::default(), format, &data).unwrap());
+        }
+    }
+}
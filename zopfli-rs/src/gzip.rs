@@ -0,0 +1,225 @@
+// Copyright Anysphere Inc.
+// gzip (RFC 1952) container output
+
+use crate::types::Options;
+use crate::deflate::deflate;
+use crate::error::ZopfliError;
+
+/// Table of CRCs of all 8-bit messages.
+const CRC_TABLE: [u32; 256] = make_crc_table();
+
+/// Makes the table for a fast CRC.
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            if c & 1 != 0 {
+                c = 0xedb88320 ^ (c >> 1);
+            } else {
+                c >>= 1;
+            }
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// Updates a running crc with the bytes buf. The crc should be initialized to
+/// zero, and the result of each call passed to the next one.
+pub fn update_crc(crc: u32, buf: &[u8]) -> u32 {
+    let mut c = crc ^ 0xffffffff;
+    for &byte in buf {
+        c = CRC_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    c ^ 0xffffffff
+}
+
+/// Returns the CRC-32 of the bytes buf.
+pub fn crc32(buf: &[u8]) -> u32 {
+    update_crc(0, buf)
+}
+
+/// Flag bit: an extra field is present.
+const FEXTRA: u8 = 4;
+
+/// Flag bit: an original file name is present.
+const FNAME: u8 = 8;
+
+/// Optional fields of the gzip member header.
+#[derive(Debug, Clone)]
+pub struct GzipHeader {
+    /// Modification time of the original file, in seconds since the Unix epoch,
+    /// or 0 if not available.
+    pub mtime: u32,
+
+    /// Operating system the file was compressed on. Default value: 3 (Unix).
+    pub os: u8,
+
+    /// Original file name, stored in the FNAME field. Must not contain NUL bytes.
+    pub filename: Option<Vec<u8>>,
+
+    /// Contents of the FEXTRA field. Must be at most 65535 bytes long.
+    pub extra: Option<Vec<u8>>,
+}
+
+impl Default for GzipHeader {
+    fn default() -> Self {
+        GzipHeader {
+            mtime: 0,
+            os: 3,
+            filename: None,
+            extra: None,
+        }
+    }
+}
+
+/// Checks that the header fields can be encoded.
+pub fn check_gzip_header(header: &GzipHeader) -> Result<(), ZopfliError> {
+    if header.extra.as_ref().is_some_and(|extra| extra.len() > u16::MAX as usize) {
+        return Err(ZopfliError::InvalidHeader("extra field is longer than 65535 bytes"));
+    }
+    if header.filename.as_ref().is_some_and(|filename| filename.contains(&0)) {
+        return Err(ZopfliError::InvalidHeader("file name contains a NUL byte"));
+    }
+    Ok(())
+}
+
+/// Appends the gzip member header to out. Returns InvalidHeader, without
+/// appending anything, if the header does not pass check_gzip_header.
+pub fn write_gzip_header(header: &GzipHeader, out: &mut Vec<u8>) -> Result<(), ZopfliError> {
+    check_gzip_header(header)?;
+    let mut flags = 0;
+    if header.extra.is_some() {
+        flags |= FEXTRA;
+    }
+    if header.filename.is_some() {
+        flags |= FNAME;
+    }
+
+    out.push(31); // ID1
+    out.push(139); // ID2
+    out.push(8); // CM
+    out.push(flags); // FLG
+    out.extend_from_slice(&header.mtime.to_le_bytes()); // MTIME
+    out.push(2); // XFL, 2 indicates best compression.
+    out.push(header.os); // OS
+
+    if let Some(extra) = &header.extra {
+        out.extend_from_slice(&(extra.len() as u16).to_le_bytes()); // XLEN
+        out.extend_from_slice(extra);
+    }
+    if let Some(filename) = &header.filename {
+        out.extend_from_slice(filename);
+        out.push(0);
+    }
+    Ok(())
+}
+
+/// Appends the gzip member trailer: the CRC-32 and size modulo 2^32 of the
+/// uncompressed data.
+pub fn write_gzip_trailer(crc: u32, insize: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&crc.to_le_bytes()); // CRC
+    out.extend_from_slice(&insize.to_le_bytes()); // ISIZE
+}
+
+/// Compresses the data according to the gzip specification, RFC 1952, with a
+/// default header.
+pub fn gzip_compress(options: &Options, input: &[u8]) -> Result<Vec<u8>, ZopfliError> {
+    gzip_compress_with_header(options, &GzipHeader::default(), input)
+}
+
+/// Compresses the data according to the gzip specification, RFC 1952, using the
+/// given header fields.
+pub fn gzip_compress_with_header(
+    options: &Options,
+    header: &GzipHeader,
+    input: &[u8],
+) -> Result<Vec<u8>, ZopfliError> {
+    let mut out = Vec::new();
+    write_gzip_header(header, &mut out)?;
+    out.extend_from_slice(&deflate(options, 2, input)?);
+    write_gzip_trailer(crc32(input), input.len() as u32, &mut out);
+
+    if options.verbose {
+        eprintln!(
+            "Original Size: {}, Gzip: {}, Compression: {:.2}% Removed",
+            input.len(), out.len(),
+            100.0 * (input.len() as f64 - out.len() as f64) / input.len() as f64
+        );
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+    }
+
+    #[test]
+    fn test_update_crc_incremental() {
+        let data = b"incremental crc over several pieces";
+        let crc = data.chunks(7).fold(0, update_crc);
+        assert_eq!(crc, crc32(data));
+    }
+
+    #[test]
+    fn test_default_header() {
+        let mut out = Vec::new();
+        write_gzip_header(&GzipHeader::default(), &mut out).unwrap();
+        assert_eq!(out, vec![31, 139, 8, 0, 0, 0, 0, 0, 2, 3]);
+    }
+
+    #[test]
+    fn test_header_optional_fields() {
+        let header = GzipHeader {
+            mtime: 0x01020304,
+            os: 0,
+            filename: Some(b"a.txt".to_vec()),
+            extra: Some(vec![0xaa, 0xbb]),
+        };
+        let mut out = Vec::new();
+        write_gzip_header(&header, &mut out).unwrap();
+        assert_eq!(out[3], FEXTRA | FNAME);
+        assert_eq!(&out[4..8], &[4, 3, 2, 1]);
+        assert_eq!(out[9], 0);
+        // FEXTRA comes before FNAME.
+        assert_eq!(&out[10..14], &[2, 0, 0xaa, 0xbb]);
+        assert_eq!(&out[14..], b"a.txt\0");
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let header = GzipHeader { filename: Some(b"a\0b".to_vec()), ..GzipHeader::default() };
+        assert!(matches!(
+            gzip_compress_with_header(&Options::default(), &header, b"x"),
+            Err(ZopfliError::InvalidHeader(_))
+        ));
+        let header = GzipHeader { extra: Some(vec![0; 65536]), ..GzipHeader::default() };
+        assert!(check_gzip_header(&header).is_err());
+        let mut out = Vec::new();
+        assert!(write_gzip_header(&header, &mut out).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_gzip_compress_trailer() {
+        let data = b"gzip gzip gzip gzip";
+        let out = gzip_compress(&Options::default(), data).unwrap();
+        let n = out.len();
+        assert_eq!(&out[..3], &[31, 139, 8]);
+        assert_eq!(&out[n - 8..n - 4], &crc32(data).to_le_bytes());
+        assert_eq!(&out[n - 4..], &(data.len() as u32).to_le_bytes());
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright Anysphere Inc.
 // Hash table implementation for LZ77 pattern matching
 
-use crate::types::{Hash, WINDOW_SIZE, WINDOW_MASK, MIN_MATCH};
+use crate::types::{Hash, WINDOW_MASK, MIN_MATCH};
 
 const HASH_SHIFT: i32 = 5;
 const HASH_MASK: i32 = 32767;
@@ -26,8 +26,7 @@ pub fn warmup_hash(array: &[u8], pos: usize, end: usize, h: &mut Hash) {
 /// Updates the hash values based on the current position in the array. All calls
 /// to this must be made for consecutive bytes.
 pub fn update_hash(array: &[u8], pos: usize, end: usize, h: &mut Hash) {
-    let hpos = (pos & WINDOW_MASK) as usize;
-    let mut amount: usize = 0;
+    let hpos = pos & WINDOW_MASK;
     
     let next_char = if pos + MIN_MATCH <= end {
         array[pos + MIN_MATCH - 1]
@@ -44,18 +43,9 @@ pub fn update_hash(array: &[u8], pos: usize, end: usize, h: &mut Hash) {
     }
     h.head[h.val as usize] = hpos as i32;
     
-    // Update "same"
-    if h.same[((pos.wrapping_sub(1)) & WINDOW_MASK) as usize] > 1 {
-        amount = h.same[((pos.wrapping_sub(1)) & WINDOW_MASK) as usize] as usize - 1;
-    }
-    while pos + amount + 1 < end 
-        && array[pos] == array[pos + amount + 1] 
-        && amount < u16::MAX as usize {
-        amount += 1;
-    }
-    h.same[hpos] = amount as u16;
+    update_same(array, pos, end, &mut h.same);
     
-    h.val2 = (((h.same[hpos] as i32 - MIN_MATCH as i32) & 255) ^ h.val) as i32;
+    h.val2 = ((h.same[hpos] as i32 - MIN_MATCH as i32) & 255) ^ h.val;
     h.hashval2[hpos] = h.val2;
     if h.head2[h.val2 as usize] != -1 && h.hashval2[h.head2[h.val2 as usize] as usize] == h.val2 {
         h.prev2[hpos] = h.head2[h.val2 as usize] as u16;
@@ -65,6 +55,23 @@ pub fn update_hash(array: &[u8], pos: usize, end: usize, h: &mut Hash) {
     h.head2[h.val2 as usize] = hpos as i32;
 }
 
+/// Updates same, which holds per window position the amount of repetitions of
+/// the byte at that position directly after it. All calls to this must be made
+/// for consecutive bytes.
+pub fn update_same(array: &[u8], pos: usize, end: usize, same: &mut [u16]) {
+    let hpos = pos & WINDOW_MASK;
+    let mut amount: usize = 0;
+    if same[pos.wrapping_sub(1) & WINDOW_MASK] > 1 {
+        amount = same[pos.wrapping_sub(1) & WINDOW_MASK] as usize - 1;
+    }
+    while pos + amount + 1 < end 
+        && array[pos] == array[pos + amount + 1] 
+        && amount < u16::MAX as usize {
+        amount += 1;
+    }
+    same[hpos] = amount as u16;
+}
+
 /// Resets all fields of Hash.
 pub fn reset_hash(h: &mut Hash) {
     h.val = 0;
@@ -88,6 +95,7 @@ pub fn reset_hash(h: &mut Hash) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::WINDOW_SIZE;
     
     #[test]
     fn test_hash_creation() {
@@ -154,7 +162,7 @@ mod tests {
         for i in 0..data.len() - MIN_MATCH {
             update_hash(data, i, data.len(), &mut hash);
             // With repeated bytes, 'same' should be high
-            let hpos = (i & WINDOW_MASK) as usize;
+            let hpos = i & WINDOW_MASK;
             if i > 0 {
                 assert!(hash.same[hpos] > 0);
             }
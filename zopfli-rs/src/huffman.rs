@@ -115,9 +115,8 @@ fn init_lists(pool: &mut NodePool, leaves: &[Node], maxbits: usize, lists: &mut
     init_node(leaves[0].weight, 1, usize::MAX, pool.get_mut(node0_idx));
     init_node(leaves[1].weight, 2, usize::MAX, pool.get_mut(node1_idx));
     
-    for i in 0..maxbits {
-        lists[i][0] = node0_idx;
-        lists[i][1] = node1_idx;
+    for list in lists.iter_mut().take(maxbits) {
+        *list = [node0_idx, node1_idx];
     }
 }
 
@@ -233,16 +232,14 @@ pub fn length_limited_code_lengths(
     bitlengths: &mut [u32],
 ) -> Result<(), &'static str> {
     // Initialize all bitlengths at 0
-    for i in 0..n {
-        bitlengths[i] = 0;
-    }
+    bitlengths[..n].fill(0);
     
     // Count used symbols and place them in the leaves
     let mut leaves = Vec::new();
-    for i in 0..n {
-        if frequencies[i] > 0 {
+    for (i, &frequency) in frequencies.iter().enumerate().take(n) {
+        if frequency > 0 {
             leaves.push(Node {
-                weight: frequencies[i],
+                weight: frequency,
                 count: i as i32,
                 tail: usize::MAX,
             });
@@ -319,17 +316,12 @@ pub fn lengths_to_symbols(lengths: &[u32], n: usize, maxbits: u32, symbols: &mut
     let mut next_code = vec![0usize; maxbits as usize + 1];
     
     // Initialize symbols
-    for i in 0..n {
-        symbols[i] = 0;
-    }
+    symbols[..n].fill(0);
     
     // 1) Count the number of codes for each code length
-    for bits in 0..=maxbits as usize {
-        bl_count[bits] = 0;
-    }
-    for i in 0..n {
-        debug_assert!(lengths[i] <= maxbits);
-        bl_count[lengths[i] as usize] += 1;
+    for &length in &lengths[..n] {
+        debug_assert!(length <= maxbits);
+        bl_count[length as usize] += 1;
     }
     
     // 2) Find the numerical value of the smallest code for each code length
@@ -350,21 +342,36 @@ pub fn lengths_to_symbols(lengths: &[u32], n: usize, maxbits: u32, symbols: &mut
     }
 }
 
-/// Calculates the entropy of each symbol, based on the counts of each symbol.
-pub fn calculate_entropy(count: &[usize], n: usize, bitlengths: &mut [f64]) {
-    let mut sum = 0usize;
+/// Reverses the bits of the first n symbols, each within its code length, since
+/// deflate stores Huffman codes starting at the most significant bit but packs
+/// everything else starting at the least significant one.
+pub fn reverse_symbols(lengths: &[u32], n: usize, symbols: &mut [u32]) {
     for i in 0..n {
-        sum += count[i];
+        if lengths[i] != 0 {
+            symbols[i] = symbols[i].reverse_bits() >> (32 - lengths[i]);
+        }
     }
+}
+
+/// Calculates the entropy of each symbol, based on the counts of each symbol.
+pub fn calculate_entropy(count: &[usize], n: usize, bitlengths: &mut [f64]) {
+    let sum: usize = count[..n].iter().sum();
     
-    let log2sum = (sum as f64).log2();
+    let log2sum = if sum == 0 { (n as f64).log2() } else { (sum as f64).log2() };
     for i in 0..n {
-        // Cannot take log(0)
+        // When the count of the symbol is 0, but its cost is requested anyway, it
+        // means the symbol will appear at least once anyway, so give it the cost
+        // as if its count is 1.
         if count[i] == 0 {
-            bitlengths[i] = 0.0;
+            bitlengths[i] = log2sum;
         } else {
             bitlengths[i] = log2sum - (count[i] as f64).log2();
         }
+        // Clamp tiny negative results of the floating point subtraction to zero.
+        if bitlengths[i] < 0.0 && bitlengths[i] > -1e-5 {
+            bitlengths[i] = 0.0;
+        }
+        debug_assert!(bitlengths[i] >= 0.0);
     }
 }
 
@@ -445,7 +452,15 @@ mod tests {
             }
         }
     }
-    
+
+    #[test]
+    fn test_reverse_symbols() {
+        let lengths = vec![3, 0, 1, 15];
+        let mut symbols = vec![0b110, 0, 1, 0b100000000000011];
+        reverse_symbols(&lengths, 4, &mut symbols);
+        assert_eq!(symbols, vec![0b011, 0, 1, 0b110000000000001]);
+    }
+
     #[test]
     fn test_calculate_entropy() {
         let counts = vec![10, 20, 30, 40];
@@ -466,6 +481,7 @@ mod tests {
         
         calculate_entropy(&counts, 3, &mut bitlengths);
         
-        assert_eq!(bitlengths[1], 0.0); // Zero count should give 0 bits
+        // Zero count is costed as if it occurred once
+        assert_eq!(bitlengths[1], (40.0f64).log2());
     }
 }
@@ -0,0 +1,444 @@
+// Copyright Anysphere Inc.
+// Deflate (RFC 1951) decoder, used to verify the compressed output
+
+use std::fmt;
+
+use crate::symbols::{get_length_symbol_extra_bits, get_dist_symbol_extra_bits};
+
+/// Maximum bits in a code.
+const MAX_BITS: usize = 15;
+
+/// Maximum number of literal/length codes.
+const MAX_LCODES: usize = 286;
+
+/// Maximum number of distance codes.
+const MAX_DCODES: usize = 30;
+
+/// Number of fixed literal/length codes.
+const FIX_LCODES: usize = 288;
+
+/// Order in which the code length code lengths are stored.
+const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Base lengths for length symbols 257..285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+/// Base distances for distance symbols 0..29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Errors that can occur while decoding a deflate stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    /// The input ended before the final block was complete.
+    UnexpectedEof,
+    /// A block header has the reserved block type 3.
+    InvalidBlockType,
+    /// The LEN and NLEN fields of a stored block do not match.
+    InvalidStoredLength,
+    /// The code lengths of a dynamic block do not describe a valid Huffman
+    /// code, or the header counts are out of range.
+    InvalidCodeLengths,
+    /// A literal/length or distance symbol is not allowed by the format.
+    InvalidSymbol,
+    /// A distance refers back before the start of the output.
+    DistanceTooFar,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            InflateError::UnexpectedEof => "unexpected end of deflate input",
+            InflateError::InvalidBlockType => "invalid deflate block type",
+            InflateError::InvalidStoredLength => "stored block length does not match its complement",
+            InflateError::InvalidCodeLengths => "invalid Huffman code lengths",
+            InflateError::InvalidSymbol => "invalid literal/length or distance symbol",
+            InflateError::DistanceTooFar => "distance too far back",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for InflateError {}
+
+/// Reads bits from the input, starting at the least significant bit of each
+/// byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bp: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, bp: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<u32, InflateError> {
+        let byte = *self.data.get(self.pos).ok_or(InflateError::UnexpectedEof)?;
+        let bit = ((byte >> self.bp) & 1) as u32;
+        self.bp += 1;
+        if self.bp == 8 {
+            self.bp = 0;
+            self.pos += 1;
+        }
+        Ok(bit)
+    }
+
+    /// Reads need bits, the first one read ending up in the least significant
+    /// bit of the result.
+    fn read_bits(&mut self, need: u32) -> Result<u32, InflateError> {
+        let mut value = 0;
+        for i in 0..need {
+            value |= self.read_bit()? << i;
+        }
+        Ok(value)
+    }
+
+    /// Discards the remaining bits of the current byte.
+    fn align_to_byte(&mut self) {
+        if self.bp != 0 {
+            self.bp = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// Canonical Huffman decoding table: the number of codes of each length and
+/// the symbols ordered by code.
+struct Huffman {
+    count: [u16; MAX_BITS + 1],
+    symbol: Vec<u16>,
+}
+
+impl Huffman {
+    /// Builds the decoding table from the code lengths. Incomplete codes are
+    /// only accepted when they are empty or consist of a single code of length
+    /// 1, as a block may use no distances or only one.
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut count = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            count[len as usize] += 1;
+        }
+
+        // Check for an over-subscribed or incomplete set of lengths.
+        let mut left: i32 = 1;
+        for &len_count in &count[1..=MAX_BITS] {
+            left <<= 1;
+            left -= len_count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+        let used = lengths.len() - count[0] as usize;
+        if left > 0 && !(used == 1 && count[1] == 1) && used != 0 {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+
+        let mut offs = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offs[len + 1] = offs[len] + count[len];
+        }
+        let mut symbol = vec![0u16; used];
+        for (i, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbol[offs[len as usize] as usize] = i as u16;
+                offs[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { count, symbol })
+    }
+
+    /// Returns whether every bit sequence decodes to a symbol.
+    fn is_complete(&self) -> bool {
+        let mut left: i32 = 1;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - self.count[len] as i32;
+        }
+        left == 0
+    }
+
+    /// Decodes one symbol, reading the code bit by bit.
+    fn decode(&self, br: &mut BitReader) -> Result<usize, InflateError> {
+        let mut code: i32 = 0; // Bits of the code read so far.
+        let mut first: i32 = 0; // First code of the current length.
+        let mut index: i32 = 0; // Index of the first code of this length in symbol.
+        for len in 1..=MAX_BITS {
+            code |= br.read_bit()? as i32;
+            let count = self.count[len] as i32;
+            if code - count < first {
+                return Ok(self.symbol[(index + (code - first)) as usize] as usize);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        // Ran out of codes, only possible with an incomplete code.
+        Err(InflateError::InvalidSymbol)
+    }
+}
+
+/// Copies a stored block to the output.
+fn inflate_stored(br: &mut BitReader, out: &mut Vec<u8>) -> Result<(), InflateError> {
+    br.align_to_byte();
+    let header = br.data.get(br.pos..br.pos + 4).ok_or(InflateError::UnexpectedEof)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let nlen = u16::from_le_bytes([header[2], header[3]]);
+    if len != !nlen {
+        return Err(InflateError::InvalidStoredLength);
+    }
+    br.pos += 4;
+    let block = br.data.get(br.pos..br.pos + len as usize).ok_or(InflateError::UnexpectedEof)?;
+    out.extend_from_slice(block);
+    br.pos += len as usize;
+    Ok(())
+}
+
+/// Decodes the literal, length and distance symbols of a Huffman compressed
+/// block until the end symbol.
+fn inflate_codes(
+    br: &mut BitReader,
+    lencode: &Huffman,
+    distcode: &Huffman,
+    out: &mut Vec<u8>,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = lencode.decode(br)?;
+        if symbol < 256 {
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let symbol = symbol - 257;
+            if symbol >= LENGTH_BASE.len() {
+                return Err(InflateError::InvalidSymbol);
+            }
+            let length = LENGTH_BASE[symbol] as usize
+                + br.read_bits(get_length_symbol_extra_bits(symbol + 257) as u32)? as usize;
+
+            let dsymbol = distcode.decode(br)?;
+            if dsymbol >= MAX_DCODES {
+                return Err(InflateError::InvalidSymbol);
+            }
+            let dist = DIST_BASE[dsymbol] as usize
+                + br.read_bits(get_dist_symbol_extra_bits(dsymbol) as u32)? as usize;
+            if dist > out.len() {
+                return Err(InflateError::DistanceTooFar);
+            }
+
+            // The source may overlap the bytes being written, so copy one by one.
+            let start = out.len() - dist;
+            for i in 0..length {
+                out.push(out[start + i]);
+            }
+        }
+    }
+}
+
+/// Decodes a block with the fixed Huffman tree.
+fn inflate_fixed(br: &mut BitReader, out: &mut Vec<u8>) -> Result<(), InflateError> {
+    let mut lengths = [0u8; FIX_LCODES];
+    for (i, len) in lengths.iter_mut().enumerate() {
+        *len = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    let lencode = Huffman::new(&lengths)?;
+    // The fixed distance code is incomplete: symbols 30 and 31 never occur.
+    let distcode = Huffman::new(&[5; MAX_DCODES + 2])?;
+    inflate_codes(br, &lencode, &distcode, out)
+}
+
+/// Reads the code length code and the run length encoded literal/length and
+/// distance code lengths, then decodes the block.
+fn inflate_dynamic(br: &mut BitReader, out: &mut Vec<u8>) -> Result<(), InflateError> {
+    let nlen = br.read_bits(5)? as usize + 257;
+    let ndist = br.read_bits(5)? as usize + 1;
+    let ncode = br.read_bits(4)? as usize + 4;
+    if nlen > MAX_LCODES || ndist > MAX_DCODES {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut clcl = [0u8; 19];
+    for &index in ORDER.iter().take(ncode) {
+        clcl[index] = br.read_bits(3)? as u8;
+    }
+    let clcode = Huffman::new(&clcl)?;
+    if !clcode.is_complete() {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut index = 0;
+    while index < nlen + ndist {
+        let symbol = clcode.decode(br)?;
+        if symbol < 16 {
+            lengths[index] = symbol as u8;
+            index += 1;
+            continue;
+        }
+        let (value, repeat) = match symbol {
+            16 => {
+                if index == 0 {
+                    return Err(InflateError::InvalidCodeLengths); // Nothing to repeat.
+                }
+                (lengths[index - 1], 3 + br.read_bits(2)? as usize)
+            }
+            17 => (0, 3 + br.read_bits(3)? as usize),
+            _ => (0, 11 + br.read_bits(7)? as usize),
+        };
+        if index + repeat > nlen + ndist {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+
+    // The end of block symbol must have a code.
+    if lengths[256] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let lencode = Huffman::new(&lengths[..nlen])?;
+    let distcode = Huffman::new(&lengths[nlen..])?;
+    inflate_codes(br, &lencode, &distcode, out)
+}
+
+/// Decompresses a raw deflate stream and returns the uncompressed data. Bytes
+/// after the final block are ignored.
+pub fn inflate(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_blocks(&[], input, &mut Vec::new())
+}
+
+/// Decompresses a raw deflate stream that was compressed with the given preset
+/// dictionary.
+pub fn inflate_with_dictionary(dictionary: &[u8], input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_blocks(dictionary, input, &mut Vec::new())
+}
+
+/// Decompresses a raw deflate stream, appending the uncompressed size after each
+/// completed block to block_ends. On error, block_ends.len() is the index of the
+/// block that failed to decode.
+pub fn inflate_blocks(dictionary: &[u8], input: &[u8], block_ends: &mut Vec<usize>) -> Result<Vec<u8>, InflateError> {
+    let (out, _, _) = inflate_part(dictionary, input, 0, usize::MAX, block_ends)?;
+    Ok(out)
+}
+
+/// Decompresses the deflate blocks of input starting at bit offset start, with
+/// window as the data preceding them, until the final block or until at least
+/// size bytes are decompressed. Appends the uncompressed size after each
+/// completed block to block_ends. Returns the decompressed data, the bit offset
+/// after the last block, and whether that was the final block.
+pub(crate) fn inflate_part(
+    window: &[u8],
+    input: &[u8],
+    start: usize,
+    size: usize,
+    block_ends: &mut Vec<usize>,
+) -> Result<(Vec<u8>, usize, bool), InflateError> {
+    let mut br = BitReader::new(input);
+    br.pos = start / 8;
+    br.bp = (start % 8) as u8;
+    let mut out = window.to_vec();
+    loop {
+        let last = br.read_bit()? == 1;
+        match br.read_bits(2)? {
+            0 => inflate_stored(&mut br, &mut out)?,
+            1 => inflate_fixed(&mut br, &mut out)?,
+            2 => inflate_dynamic(&mut br, &mut out)?,
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+        block_ends.push(out.len() - window.len());
+        if last || out.len() - window.len() >= size {
+            let end = br.pos * 8 + br.bp as usize;
+            return Ok((out.split_off(window.len()), end, last));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::deflate;
+    use crate::types::Options;
+
+    #[test]
+    fn test_inflate_all_block_types() {
+        let data = b"inflate inflate inflate, the quick brown fox jumps over the lazy dog".repeat(20);
+        for btype in 0..3 {
+            let compressed = deflate(&Options::default(), btype, &data).unwrap();
+            assert_eq!(inflate(&compressed).unwrap(), data, "btype {}", btype);
+        }
+    }
+
+    #[test]
+    fn test_inflate_known_streams() {
+        // Empty fixed block.
+        assert_eq!(inflate(&[0x03, 0x00]).unwrap(), b"");
+        // Stored block containing "abc".
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c']).unwrap(), b"abc");
+        // Fixed block from zlib: "aaaaaaaaaa", a literal followed by an overlapping match.
+        assert_eq!(inflate(&[0x4b, 0x4c, 0x84, 0x01, 0x00]).unwrap(), b"aaaaaaaaaa");
+    }
+
+    #[test]
+    fn test_inflate_errors() {
+        assert_eq!(inflate(&[]), Err(InflateError::UnexpectedEof));
+        assert_eq!(inflate(&[0x07]), Err(InflateError::InvalidBlockType));
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0x00, 0x00]), Err(InflateError::InvalidStoredLength));
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'a']), Err(InflateError::UnexpectedEof));
+        // Fixed block whose first symbol is a match with distance 1.
+        assert_eq!(inflate(&[0x03, 0x02]), Err(InflateError::DistanceTooFar));
+        // Dynamic block whose code length code lengths are all zero.
+        assert_eq!(inflate(&[0x05, 0x00, 0x00, 0x00]), Err(InflateError::InvalidCodeLengths));
+    }
+
+    #[test]
+    fn test_inflate_truncated() {
+        let data = b"truncated truncated truncated data".repeat(10);
+        let compressed = deflate(&Options::default(), 2, &data).unwrap();
+        for len in 0..compressed.len() {
+            assert!(inflate(&compressed[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_inflate_blocks() {
+        // Two stored blocks, "ab" then "c".
+        let data = [0x00, 0x02, 0x00, 0xfd, 0xff, b'a', b'b', 0x01, 0x01, 0x00, 0xfe, 0xff, b'c'];
+        let mut block_ends = Vec::new();
+        assert_eq!(inflate_blocks(&[], &data, &mut block_ends).unwrap(), b"abc");
+        assert_eq!(block_ends, vec![2, 3]);
+
+        let mut block_ends = Vec::new();
+        assert!(inflate_blocks(&[], &data[..10], &mut block_ends).is_err());
+        assert_eq!(block_ends, vec![2]);
+    }
+
+    #[test]
+    fn test_inflate_with_dictionary() {
+        let dictionary = b"dictionary words ";
+        let data = b"words from the dictionary";
+        let compressed = crate::deflate::deflate_with_dictionary(&Options::default(), 2, dictionary, data).unwrap();
+        assert_eq!(inflate_with_dictionary(dictionary, &compressed).unwrap(), data);
+        assert_eq!(inflate(&compressed), Err(InflateError::DistanceTooFar));
+    }
+
+    #[test]
+    fn test_huffman_rejects_oversubscribed() {
+        assert!(Huffman::new(&[1, 1, 1]).is_err());
+        assert!(Huffman::new(&[1, 2, 2]).is_ok());
+        // Single code of length 1 is the allowed incomplete code.
+        assert!(Huffman::new(&[0, 1, 0]).is_ok());
+        assert!(Huffman::new(&[2, 2, 0]).is_err());
+    }
+}
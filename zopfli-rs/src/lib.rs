@@ -6,13 +6,33 @@ pub mod symbols;
 pub mod util;
 pub mod huffman;
 pub mod hash;
+pub mod matchfinder;
 pub mod cache;
 pub mod lz77;
 pub mod block;
 pub mod split;
 pub mod deflate;
+pub mod gzip;
+pub mod zlib;
+pub mod format;
+pub mod inflate;
+pub mod verify;
+pub mod error;
+pub mod encoder;
+pub mod reader;
 
-pub use types::{Options, LZ77Store, BlockState};
+pub use types::{Options, OptionsBuilder, LZ77Store, BlockState};
+pub use lz77::{CostModel, FixedCostModel};
+pub use matchfinder::{MatchFinder, MatchFinderKind};
+pub use deflate::{deflate, deflate_to};
+pub use gzip::gzip_compress;
+pub use zlib::zlib_compress;
+pub use format::{compress, Format};
+pub use inflate::{inflate, InflateError};
+pub use verify::VerifyError;
+pub use error::ZopfliError;
+pub use encoder::{DeflateEncoder, GzipEncoder, ZlibEncoder};
+pub use reader::{DeflateReader, GzipReader, ZlibReader};
 
 #[cfg(test)]
 mod tests {
@@ -1,15 +1,16 @@
 // Copyright Anysphere Inc.
 // LZ77 compression implementation
 
-use crate::types::{LZ77Store, BlockState, Hash, MIN_MATCH, MAX_MATCH, WINDOW_SIZE, WINDOW_MASK};
-use crate::symbols::{get_length_symbol, get_dist_symbol};
-use crate::hash::{update_hash, warmup_hash, reset_hash};
+use crate::types::{LZ77Store, BlockState, SymbolStats, RanState, MIN_MATCH, MAX_MATCH, WINDOW_SIZE, NUM_LL, NUM_D, LARGE_FLOAT};
+use crate::symbols::{get_length_symbol, get_dist_symbol, get_length_extra_bits, get_dist_extra_bits};
+use crate::matchfinder::MatchFinder;
 use crate::cache::{try_get_from_longest_match_cache, store_in_longest_match_cache};
-
-const MAX_CHAIN_HITS: usize = 8192;
+use crate::huffman::calculate_entropy;
+use crate::block::calculate_block_size;
+use crate::error::{check_range, ZopfliError};
 
 /// Verifies if length and dist are indeed valid, only used for assertion.
-pub fn verify_len_dist(data: &[u8], datasize: usize, pos: usize, dist: u16, length: u16) {
+pub(crate) fn verify_len_dist(data: &[u8], datasize: usize, pos: usize, dist: u16, length: u16) {
     debug_assert!(pos + length as usize <= datasize);
     for i in 0..length as usize {
         debug_assert_eq!(
@@ -21,45 +22,45 @@ pub fn verify_len_dist(data: &[u8], datasize: usize, pos: usize, dist: u16, leng
     }
 }
 
-/// Finds how long the match of scan and match is.
-fn get_match(
+/// Finds how long the match of scan and match is, comparing the bytes from pos
+/// and match_pos on up to end.
+/// Compares 8 bytes at a time: the XOR of two little-endian words has its lowest
+/// set bit in the first byte that differs.
+pub(crate) fn get_match(
     array: &[u8],
     pos: usize,
     match_pos: usize,
     end: usize,
 ) -> usize {
-    let mut scan_pos = pos;
-    let mut match_idx = match_pos;
-    
-    // Compare 8 bytes at a time when possible
-    while scan_pos + 8 <= end {
-        if array.len() >= match_idx + 8 && array.len() >= scan_pos + 8 {
-            // Safe to compare 8 bytes
-            let scan_chunk = &array[scan_pos..scan_pos + 8];
-            let match_chunk = &array[match_idx..match_idx + 8];
-            
-            if scan_chunk == match_chunk {
-                scan_pos += 8;
-                match_idx += 8;
-                continue;
-            }
-        }
-        break;
-    }
+    let len = end - pos;
+    let scan = &array[pos..end];
+    let matched = &array[match_pos..match_pos + len];
     
-    // Compare remaining bytes one by one
-    while scan_pos < end && array[scan_pos] == array[match_idx] {
-        scan_pos += 1;
-        match_idx += 1;
+    let mut scan_words = scan.chunks_exact(8);
+    let mut match_words = matched.chunks_exact(8);
+    let mut i = 0;
+    for (a, b) in (&mut scan_words).zip(&mut match_words) {
+        let a = u64::from_le_bytes(a.try_into().unwrap());
+        let b = u64::from_le_bytes(b.try_into().unwrap());
+        let diff = a ^ b;
+        if diff != 0 {
+            return i + (diff.trailing_zeros() / 8) as usize;
+        }
+        i += 8;
     }
     
-    scan_pos - pos
+    // Compare the remaining bytes one by one
+    let tail = scan_words.remainder().iter().zip(match_words.remainder());
+    i + tail.take_while(|(a, b)| a == b).count()
 }
 
-/// Finds the longest match (length and corresponding distance) for LZ77 compression.
-pub fn find_longest_match(
+/// Finds the longest match (length and corresponding distance) for LZ77 compression,
+/// using the longest match cache of the block state if it has one, and the
+/// match finder otherwise. The match finder must have been updated up to pos.
+#[allow(clippy::too_many_arguments)] // Mirrors the C signature.
+pub(crate) fn find_longest_match<F: MatchFinder + ?Sized>(
     s: &mut BlockState,
-    h: &Hash,
+    h: &mut F,
     array: &[u8],
     pos: usize,
     size: usize,
@@ -89,105 +90,9 @@ pub fn find_longest_match(
         limit = size - pos;
     }
     
-    let hpos = (pos & WINDOW_MASK) as u16;
     let mut bestdist = 0u16;
     let mut bestlength = 1u16;
-    
-    let mut hhead = &h.head;
-    let mut hprev = &h.prev;
-    let mut hhashval = &h.hashval;
-    let mut hval = h.val;
-    
-    debug_assert!((hval as usize) < 65536);
-    
-    let mut pp = hhead[hval as usize];
-    if pp < 0 {
-        *length = bestlength;
-        *distance = bestdist;
-        return;
-    }
-    
-    let mut p = hprev[pp as usize];
-    let mut dist = if (p as u16) < hpos {
-        hpos - p as u16
-    } else {
-        (WINDOW_SIZE as u16 - p as u16) + hpos
-    };
-    
-    let mut chain_counter = MAX_CHAIN_HITS;
-    
-    // Go through all distances
-    while (dist as usize) < WINDOW_SIZE {
-        debug_assert!((p as usize) < WINDOW_SIZE);
-        debug_assert_eq!(p, hprev[pp as usize] as u16);
-        debug_assert_eq!(hhashval[p as usize], hval);
-        
-        if dist > 0 {
-            debug_assert!(pos < size);
-            debug_assert!((dist as usize) <= pos);
-            
-            let scan_pos = pos;
-            let match_pos = pos - dist as usize;
-            
-            // Testing the byte at position bestlength first, goes slightly faster
-            let mut currentlength = 0usize;
-            if pos + bestlength as usize >= size || 
-               array[scan_pos + bestlength as usize] == array[match_pos + bestlength as usize] {
-                
-                let same0 = h.same[(pos & WINDOW_MASK) as usize];
-                if same0 > 2 && array[scan_pos] == array[match_pos] {
-                    let same1 = h.same[((pos - dist as usize) & WINDOW_MASK) as usize];
-                    let same = if same0 < same1 { same0 } else { same1 };
-                    let same = if same as usize > limit { limit as u16 } else { same };
-                    
-                    currentlength = same as usize;
-                }
-                
-                let remaining = get_match(array, scan_pos + currentlength, match_pos + currentlength, scan_pos + limit);
-                currentlength += remaining;
-            }
-            
-            if currentlength > bestlength as usize {
-                if let Some(ref mut sublen_arr) = sublen {
-                    for j in (bestlength as usize + 1)..=currentlength {
-                        sublen_arr[j] = dist;
-                    }
-                }
-                bestdist = dist;
-                bestlength = currentlength as u16;
-                if currentlength >= limit {
-                    break;
-                }
-            }
-        }
-        
-        // Switch to the other hash once this will be more efficient
-        if hhead as *const _ != &h.head2 as *const _ && bestlength >= h.same[hpos as usize] &&
-           h.val2 == h.hashval2[p as usize] {
-            hhead = &h.head2;
-            hprev = &h.prev2;
-            hhashval = &h.hashval2;
-            hval = h.val2;
-        }
-        
-        pp = p as i32;
-        p = hprev[p as usize];
-        if p == pp as u16 {
-            break; // Uninited prev value
-        }
-        
-        let new_dist = if (p as u16) < (pp as u16) {
-            (pp as u16) - (p as u16)
-        } else {
-            (WINDOW_SIZE as u16 - p as u16) + (pp as u16)
-        };
-        dist = dist + new_dist;
-        
-        chain_counter -= 1;
-        if chain_counter == 0 {
-            break;
-        }
-    }
+    h.find(array, pos, size, limit, sublen.as_deref_mut(), &mut bestdist, &mut bestlength);
     
     store_in_longest_match_cache(s, pos, limit, sublen.map(|s| &s[..]), bestdist, bestlength);
     
@@ -199,14 +104,12 @@ pub fn find_longest_match(
 
 /// Appends the length and distance to the LZ77 arrays of the LZ77Store.
 pub fn store_lit_len_dist(length: u16, dist: u16, pos: usize, store: &mut LZ77Store) {
-    use crate::types::{NUM_LL, NUM_D};
-    
     let origsize = store.size();
     let llstart = NUM_LL * (origsize / NUM_LL);
     let dstart = NUM_D * (origsize / NUM_D);
     
     // Everytime the index wraps around, a new cumulative histogram is made
-    if origsize % NUM_LL == 0 {
+    if origsize.is_multiple_of(NUM_LL) {
         for i in 0..NUM_LL {
             let val = if origsize == 0 {
                 0
@@ -216,7 +119,7 @@ pub fn store_lit_len_dist(length: u16, dist: u16, pos: usize, store: &mut LZ77St
             store.ll_counts.push(val);
         }
     }
-    if origsize % NUM_D == 0 {
+    if origsize.is_multiple_of(NUM_D) {
         for i in 0..NUM_D {
             let val = if origsize == 0 {
                 0
@@ -246,6 +149,11 @@ pub fn store_lit_len_dist(length: u16, dist: u16, pos: usize, store: &mut LZ77St
     }
 }
 
+/// Appends all LZ77 symbols of store to the end of target.
+pub fn append_lz77_store(store: &LZ77Store, target: &mut LZ77Store) {
+    target.append(store);
+}
+
 /// Gets length score for greedy algorithm
 fn get_length_score(length: u16, dist: u16) -> i32 {
     // Typically, longer matches are better, but if the distance is very large,
@@ -265,24 +173,35 @@ fn get_length_score(length: u16, dist: u16) -> i32 {
     length_score - dist_penalty
 }
 
+/// Checks that instart..inend is a range of input, and that the longest match
+/// cache of the block state, if it has one, covers it.
+fn check_block_range(s: &BlockState, input: &[u8], instart: usize, inend: usize) -> Result<(), ZopfliError> {
+    check_range(input, instart, inend)?;
+    if let Some(lmc) = &s.lmc {
+        if instart < s.blockstart || inend - s.blockstart > lmc.length.len() {
+            return Err(ZopfliError::InvalidOptions("range is not covered by the block state"));
+        }
+    }
+    Ok(())
+}
+
 /// Does LZ77 using an algorithm similar to gzip, with lazy matching.
-pub fn lz77_greedy(
+/// Returns InvalidOptions if instart..inend is not a range of input covered by
+/// the block state.
+pub fn lz77_greedy<F: MatchFinder + ?Sized>(
     s: &mut BlockState,
     input: &[u8],
     instart: usize,
     inend: usize,
     store: &mut LZ77Store,
-    h: &mut Hash,
-) {
+    h: &mut F,
+) -> Result<(), ZopfliError> {
+    check_block_range(s, input, instart, inend)?;
     if instart == inend {
-        return;
+        return Ok(());
     }
     
-    let windowstart = if instart > WINDOW_SIZE {
-        instart - WINDOW_SIZE
-    } else {
-        0
-    };
+    let windowstart = instart.saturating_sub(WINDOW_SIZE);
     
     let mut dummysublen = [0u16; 259];
     
@@ -291,16 +210,14 @@ pub fn lz77_greedy(
     let mut prev_match = 0u16;
     let mut match_available = false;
     
-    reset_hash(h);
-    warmup_hash(input, windowstart, inend, h);
-    
+    h.reset(input, windowstart, inend);
     for i in windowstart..instart {
-        update_hash(input, i, inend, h);
+        h.update(input, i, inend);
     }
     
     let mut i = instart;
     while i < inend {
-        update_hash(input, i, inend, h);
+        h.update(input, i, inend);
         
         let mut leng = 0u16;
         let mut dist = 0u16;
@@ -331,7 +248,7 @@ pub fn lz77_greedy(
                 for _ in 2..leng {
                     debug_assert!(i < inend);
                     i += 1;
-                    update_hash(input, i, inend, h);
+                    h.update(input, i, inend);
                 }
                 i += 1;
                 continue;
@@ -356,16 +273,599 @@ pub fn lz77_greedy(
         for _ in 1..leng {
             debug_assert!(i < inend);
             i += 1;
-            update_hash(input, i, inend, h);
+            h.update(input, i, inend);
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Model of the cost in bits of lit/len/dist pairs, guiding the shortest path
+/// search of the optimal parser. The C code passes a function pointer plus a
+/// context instead.
+pub trait CostModel {
+    /// Returns the cost of a literal if dist is 0, or of a length and distance
+    /// pair otherwise.
+    fn cost(&self, litlen: usize, dist: usize) -> f64;
+    
+    /// Returns the minimum possible cost this model can return for valid length
+    /// and distance symbols. The parser skips calling cost for matches that can
+    /// not improve on it, so it must not be larger than any real cost.
+    fn min_cost(&self) -> f64 {
+        // Table of distances that have a different distance symbol in the deflate
+        // specification. Each value is the first distance that has a new symbol.
+        // Only different symbols affect the cost model so only these need to be
+        // checked. See RFC 1951 section 3.2.5. Compressed blocks (length and
+        // distance codes).
+        const DSYMBOLS: [usize; 30] = [
+            1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513,
+            769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+        ];
+        
+        let mut bestlength = 0; // length that has lowest cost in the cost model
+        let mut bestdist = 0; // distance that has lowest cost in the cost model
+        
+        let mut mincost = LARGE_FLOAT;
+        for i in 3..259 {
+            let c = self.cost(i, 1);
+            if c < mincost {
+                bestlength = i;
+                mincost = c;
+            }
+        }
+        
+        mincost = LARGE_FLOAT;
+        for &dist in DSYMBOLS.iter() {
+            let c = self.cost(3, dist);
+            if c < mincost {
+                bestdist = dist;
+                mincost = c;
+            }
+        }
+        
+        self.cost(bestlength, bestdist)
+    }
+}
+
+/// Cost model based on symbol statistics, using the entropy in ll_symbols and
+/// d_symbols plus the extra bits.
+impl CostModel for SymbolStats {
+    fn cost(&self, litlen: usize, dist: usize) -> f64 {
+        if dist == 0 {
+            self.ll_symbols[litlen]
+        } else {
+            let lsym = get_length_symbol(litlen);
+            let lbits = get_length_extra_bits(litlen);
+            let dsym = get_dist_symbol(dist);
+            let dbits = get_dist_extra_bits(dist);
+            (lbits + dbits) as f64 + self.ll_symbols[lsym] + self.d_symbols[dsym]
+        }
+    }
+}
+
+/// Cost model which should exactly match fixed tree.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedCostModel;
+
+impl CostModel for FixedCostModel {
+    fn cost(&self, litlen: usize, dist: usize) -> f64 {
+        if dist == 0 {
+            if litlen <= 143 { 8.0 } else { 9.0 }
+        } else {
+            let dbits = get_dist_extra_bits(dist);
+            let lbits = get_length_extra_bits(litlen);
+            let lsym = get_length_symbol(litlen);
+            let mut cost = if lsym <= 279 { 7 } else { 8 };
+            cost += 5; // Every dist symbol has length 5.
+            (cost + dbits + lbits) as f64
         }
+    }
+}
+
+/// Performs the forward pass for "squeeze". Gets the most optimal length to reach
+/// every byte from a previous byte, using cost calculations.
+/// length_array receives, for each byte of the block, the best length to reach
+/// it from a previous byte. Returns the cost that was, according to the
+/// costmodel, needed to get to the end.
+#[allow(clippy::too_many_arguments)] // Mirrors the C signature.
+fn get_best_lengths<M: CostModel + ?Sized, F: MatchFinder + ?Sized>(
+    s: &mut BlockState,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    costmodel: &M,
+    length_array: &mut [u16],
+    h: &mut F,
+    costs: &mut [f32],
+) -> f64 {
+    let blocksize = inend - instart;
+    let mut sublen = [0u16; 259];
+    let windowstart = instart.saturating_sub(WINDOW_SIZE);
+    let mincost = costmodel.min_cost();
+    
+    if instart == inend {
+        return 0.0;
+    }
+    
+    h.reset(input, windowstart, inend);
+    for i in windowstart..instart {
+        h.update(input, i, inend);
+    }
+    
+    costs[1..=blocksize].fill(LARGE_FLOAT as f32);
+    costs[0] = 0.0; // Because it's the start.
+    length_array[0] = 0;
+    
+    let mut i = instart;
+    while i < inend {
+        let mut j = i - instart; // Index in the costs array and length_array.
+        h.update(input, i, inend);
+        
+        // If we're in a long repetition of the same character and have more than
+        // MAX_MATCH characters before and after our position.
+        if h.same(i) as usize > MAX_MATCH * 2
+            && i > instart + MAX_MATCH + 1
+            && i + MAX_MATCH * 2 + 1 < inend
+            && h.same(i - MAX_MATCH) as usize > MAX_MATCH
+        {
+            let symbolcost = costmodel.cost(MAX_MATCH, 1);
+            // Set the length to reach each one to MAX_MATCH, and the cost to the
+            // cost corresponding to that length. Doing this, we skip MAX_MATCH
+            // values to avoid calling find_longest_match.
+            for _ in 0..MAX_MATCH {
+                costs[j + MAX_MATCH] = (costs[j] as f64 + symbolcost) as f32;
+                length_array[j + MAX_MATCH] = MAX_MATCH as u16;
+                i += 1;
+                j += 1;
+                h.update(input, i, inend);
+            }
+        }
+        
+        let mut leng = 0u16;
+        let mut dist = 0u16;
+        find_longest_match(s, h, input, i, inend, MAX_MATCH, Some(&mut sublen), &mut dist, &mut leng);
+        
+        // Literal.
+        if i < inend {
+            let new_cost = costmodel.cost(input[i] as usize, 0) + costs[j] as f64;
+            debug_assert!(new_cost >= 0.0);
+            if new_cost < costs[j + 1] as f64 {
+                costs[j + 1] = new_cost as f32;
+                length_array[j + 1] = 1;
+            }
+        }
+        
+        // Lengths.
+        let kend = (leng as usize).min(inend - i);
+        let mincostaddcostj = mincost + costs[j] as f64;
+        for k in 3..=kend {
+            // Calling the cost model is expensive, avoid this if we are already at
+            // the minimum possible cost that it can return.
+            if costs[j + k] as f64 <= mincostaddcostj {
+                continue;
+            }
+            
+            let new_cost = costmodel.cost(k, sublen[k] as usize) + costs[j] as f64;
+            debug_assert!(new_cost >= 0.0);
+            if new_cost < costs[j + k] as f64 {
+                debug_assert!(k <= MAX_MATCH);
+                costs[j + k] = new_cost as f32;
+                length_array[j + k] = k as u16;
+            }
+        }
+        
         i += 1;
     }
+    
+    debug_assert!(costs[blocksize] >= 0.0);
+    costs[blocksize] as f64
+}
+
+/// Calculates the optimal path of lz77 lengths to use, from the calculated
+/// length_array. The length_array must contain the optimal length to reach that
+/// byte. The path will be filled with the lengths to use, so its data size will
+/// be the amount of lz77 symbols.
+fn trace_backwards(size: usize, length_array: &[u16], path: &mut Vec<u16>) {
+    path.clear();
+    if size == 0 {
+        return;
+    }
+    
+    let mut index = size;
+    loop {
+        path.push(length_array[index]);
+        debug_assert!(length_array[index] as usize <= index);
+        debug_assert!(length_array[index] as usize <= MAX_MATCH);
+        debug_assert!(length_array[index] != 0);
+        index -= length_array[index] as usize;
+        if index == 0 {
+            break;
+        }
+    }
+    
+    // Mirror result.
+    path.reverse();
+}
+
+/// Follows the path of lengths found by trace_backwards and stores the
+/// corresponding lit/len/dist values in the store.
+fn follow_path<F: MatchFinder + ?Sized>(
+    s: &mut BlockState,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    path: &[u16],
+    store: &mut LZ77Store,
+    h: &mut F,
+) {
+    let windowstart = instart.saturating_sub(WINDOW_SIZE);
+    
+    if instart == inend {
+        return;
+    }
+    
+    h.reset(input, windowstart, inend);
+    for i in windowstart..instart {
+        h.update(input, i, inend);
+    }
+    
+    let mut pos = instart;
+    for &step in path {
+        let mut length = step;
+        debug_assert!(pos < inend);
+        
+        h.update(input, pos, inend);
+        
+        // Add to output.
+        if length as usize >= MIN_MATCH {
+            // Get the distance by recalculating longest match. The found length
+            // should match the length from the path.
+            let mut dummy_length = 0u16;
+            let mut dist = 0u16;
+            find_longest_match(s, h, input, pos, inend, length as usize, None, &mut dist, &mut dummy_length);
+            debug_assert!(!(dummy_length != length && length > 2 && dummy_length > 2));
+            verify_len_dist(input, inend, pos, dist, length);
+            store_lit_len_dist(length, dist, pos, store);
+        } else {
+            length = 1;
+            store_lit_len_dist(input[pos] as u16, 0, pos, store);
+        }
+        
+        debug_assert!(pos + length as usize <= inend);
+        for j in 1..length as usize {
+            h.update(input, pos + j, inend);
+        }
+        
+        pos += length as usize;
+    }
+}
+
+/// Does a single run for lz77_optimal. For good compression, repeated runs with
+/// updated statistics should be performed.
+/// Returns the cost that was, according to the costmodel, needed to get to the
+/// end. This is not the actual cost.
+#[allow(clippy::too_many_arguments)] // Mirrors the C signature.
+fn lz77_optimal_run<M: CostModel + ?Sized, F: MatchFinder + ?Sized>(
+    s: &mut BlockState,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    path: &mut Vec<u16>,
+    length_array: &mut [u16],
+    costmodel: &M,
+    store: &mut LZ77Store,
+    h: &mut F,
+    costs: &mut [f32],
+) -> f64 {
+    let cost = get_best_lengths(s, input, instart, inend, costmodel, length_array, h, costs);
+    trace_backwards(inend - instart, length_array, path);
+    follow_path(s, input, instart, inend, path, store, h);
+    debug_assert!(cost < LARGE_FLOAT);
+    cost
+}
+
+/// Does the same as lz77_optimal, but optimized for the fixed tree of the
+/// deflate standard.
+/// The fixed tree never gives the best compression. But this gives the best
+/// possible LZ77 encoding possible with the fixed tree.
+/// This does not create or output any fixed tree, only LZ77 data optimized for
+/// using with a fixed tree.
+/// If instart is larger than 0, it uses values before instart as starting
+/// dictionary.
+pub fn lz77_optimal_fixed(
+    s: &mut BlockState,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    store: &mut LZ77Store,
+) -> Result<(), ZopfliError> {
+    // Shortest path for fixed tree This one should give the shortest possible
+    // result for fixed tree, no repeated runs are needed since the tree is known.
+    lz77_optimal_with_cost_model(s, input, instart, inend, &FixedCostModel, store)
+}
+
+/// Does a single shortest path run with the given cost model and appends the
+/// result to store. Unlike lz77_optimal, the model is not updated from the
+/// result, so this gives the best parse for a model that is known in advance.
+/// If instart is larger than 0, it uses values before instart as starting
+/// dictionary.
+/// Returns InvalidOptions if instart..inend is not a range of input covered by
+/// the block state.
+pub fn lz77_optimal_with_cost_model<M: CostModel + ?Sized>(
+    s: &mut BlockState,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    costmodel: &M,
+    store: &mut LZ77Store,
+) -> Result<(), ZopfliError> {
+    check_block_range(s, input, instart, inend)?;
+    // Dist to get to here with smallest cost.
+    let blocksize = inend - instart;
+    let mut length_array = vec![0u16; blocksize + 1];
+    let mut path = Vec::new();
+    let mut finder = s.options.match_finder.create();
+    let mut costs = vec![0f32; blocksize + 1];
+    
+    s.blockstart = instart;
+    s.blockend = inend;
+    
+    lz77_optimal_run(
+        s, input, instart, inend, &mut path, &mut length_array,
+        costmodel, store, &mut *finder, &mut costs,
+    );
+    Ok(())
+}
+
+/// Calculates the entropy of the statistics.
+fn calculate_statistics(stats: &mut SymbolStats) {
+    calculate_entropy(&stats.litlens, NUM_LL, &mut stats.ll_symbols);
+    calculate_entropy(&stats.dists, NUM_D, &mut stats.d_symbols);
+}
+
+/// Appends the symbol statistics from the store.
+fn get_statistics(store: &LZ77Store, stats: &mut SymbolStats) {
+    for i in 0..store.size() {
+        if store.dists[i] == 0 {
+            stats.litlens[store.litlens[i] as usize] += 1;
+        } else {
+            stats.litlens[get_length_symbol(store.litlens[i] as usize)] += 1;
+            stats.dists[get_dist_symbol(store.dists[i] as usize)] += 1;
+        }
+    }
+    stats.litlens[256] = 1; // End symbol.
+    
+    calculate_statistics(stats);
+}
+
+fn clear_stat_freqs(stats: &mut SymbolStats) {
+    stats.litlens.fill(0);
+    stats.dists.fill(0);
+}
+
+/// Get random number: "Multiply-With-Carry" generator of G. Marsaglia
+fn ran(state: &mut RanState) -> u32 {
+    state.m_z = 36969u32.wrapping_mul(state.m_z & 65535).wrapping_add(state.m_z >> 16);
+    state.m_w = 18000u32.wrapping_mul(state.m_w & 65535).wrapping_add(state.m_w >> 16);
+    (state.m_z << 16).wrapping_add(state.m_w) // 32-bit result.
+}
+
+fn randomize_freqs(state: &mut RanState, freqs: &mut [usize]) {
+    let n = freqs.len();
+    for i in 0..n {
+        if (ran(state) >> 4).is_multiple_of(3) {
+            freqs[i] = freqs[ran(state) as usize % n];
+        }
+    }
+}
+
+fn randomize_stat_freqs(state: &mut RanState, stats: &mut SymbolStats) {
+    randomize_freqs(state, &mut stats.litlens);
+    randomize_freqs(state, &mut stats.dists);
+    stats.litlens[256] = 1; // End symbol.
+}
+
+/// Adds the bit lengths.
+fn add_weighed_stat_freqs(
+    stats1: &SymbolStats,
+    w1: f64,
+    stats2: &SymbolStats,
+    w2: f64,
+    result: &mut SymbolStats,
+) {
+    for i in 0..NUM_LL {
+        result.litlens[i] = (stats1.litlens[i] as f64 * w1 + stats2.litlens[i] as f64 * w2) as usize;
+    }
+    for i in 0..NUM_D {
+        result.dists[i] = (stats1.dists[i] as f64 * w1 + stats2.dists[i] as f64 * w2) as usize;
+    }
+    result.litlens[256] = 1; // End symbol.
+}
+
+/// Calculates lit/len and dist pairs for given data.
+/// If instart is larger than 0, it uses values before instart as starting
+/// dictionary.
+/// Runs options.trials optimizations, seeded from options.seed, and keeps the
+/// smallest result; on equal sizes the earliest trial wins, so the result only
+/// depends on the options. The iterations before the statistics are first
+/// randomized do not depend on the seed, so they run once and each trial
+/// continues from their state.
+/// Returns InvalidOptions if numiterations is less than 1, or if instart..inend
+/// is not a range of input covered by the block state.
+pub fn lz77_optimal<'a>(
+    s: &mut BlockState,
+    input: &'a [u8],
+    instart: usize,
+    inend: usize,
+    numiterations: i32,
+    store: &mut LZ77Store<'a>,
+) -> Result<(), ZopfliError> {
+    if numiterations < 1 {
+        return Err(ZopfliError::InvalidOptions("numiterations must be at least 1"));
+    }
+    check_block_range(s, input, instart, inend)?;
+    // Dist to get to here with smallest cost.
+    let blocksize = inend - instart;
+    let mut scratch = OptimalScratch {
+        length_array: vec![0u16; blocksize + 1],
+        path: Vec::new(),
+        costs: vec![0f32; blocksize + 1],
+        currentstore: LZ77Store::new(input),
+        finder: s.options.match_finder.create(),
+    };
+    let mut state = OptimalState {
+        iteration: 0,
+        stats: SymbolStats::default(),
+        beststats: SymbolStats::default(),
+        bestcost: LARGE_FLOAT,
+        lastcost: 0.0,
+        lastrandomstep: -1,
+        randomize: false,
+        beststore: LZ77Store::new(input),
+    };
+
+    // Do regular deflate, then loop multiple shortest path runs, each time using
+    // the statistics of the previous run.
+
+    // Initial run.
+    lz77_greedy(s, input, instart, inend, &mut scratch.currentstore, &mut *scratch.finder)?;
+    get_statistics(&scratch.currentstore, &mut state.stats);
+    lz77_optimal_iterate(s, instart, inend, numiterations, &mut state, None, &mut scratch);
+    if !state.randomize || state.iteration == numiterations {
+        // No iterations depend on the seed, so every trial gives this result.
+        *store = state.beststore;
+        return Ok(());
+    }
+
+    let trials = s.options.trials.max(1);
+    let seed = s.options.seed;
+    let mut bestcost = LARGE_FLOAT;
+    for trial in 0..trials {
+        // Trial 0 uses the seed itself, so a single trial is unaffected by the
+        // amount of trials.
+        let trialseed = seed ^ (trial as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        let mut ran_state = RanState::from_seed(trialseed);
+        let mut trialstate = state.clone();
+        lz77_optimal_iterate(s, instart, inend, numiterations, &mut trialstate, Some(&mut ran_state), &mut scratch);
+        let trialstore = trialstate.beststore;
+        let cost = calculate_block_size(&trialstore, 0, trialstore.size(), 2);
+        if s.options.verbose_more && trials > 1 {
+            eprintln!("Trial {}: {} bit", trial, cost as i32);
+        }
+        if cost < bestcost {
+            *store = trialstore;
+            bestcost = cost;
+        }
+    }
+    Ok(())
+}
+
+/// Buffers of lz77_optimal, shared by the iterations of all trials.
+struct OptimalScratch<'a> {
+    length_array: Vec<u16>,
+    path: Vec<u16>,
+    costs: Vec<f32>,
+    currentstore: LZ77Store<'a>,
+    finder: Box<dyn MatchFinder>,
+}
+
+/// State of the iterative optimization of lz77_optimal, from which the trials
+/// branch off.
+#[derive(Clone)]
+struct OptimalState<'a> {
+    /// Next iteration to run.
+    iteration: i32,
+    stats: SymbolStats,
+    beststats: SymbolStats,
+    bestcost: f64,
+    lastcost: f64,
+    /// Last iteration after which the statistics were randomized, or -1.
+    lastrandomstep: i32,
+    /// Whether the statistics are to be randomized before the next iteration.
+    randomize: bool,
+    /// Smallest result so far.
+    beststore: LZ77Store<'a>,
+}
+
+impl OptimalState<'_> {
+    /// Try randomizing the costs a bit once the size stabilizes.
+    fn randomize_stats(&mut self, ran_state: &mut RanState) {
+        self.stats = self.beststats.clone();
+        randomize_stat_freqs(ran_state, &mut self.stats);
+        calculate_statistics(&mut self.stats);
+        self.lastrandomstep = self.iteration - 1;
+        self.randomize = false;
+    }
+}
+
+/// Runs the iterations of lz77_optimal from state.iteration on, using
+/// ran_state to randomize the statistics when the size stops improving. Without
+/// ran_state, stops at the first randomization and sets state.randomize.
+fn lz77_optimal_iterate<'a>(
+    s: &mut BlockState,
+    instart: usize,
+    inend: usize,
+    numiterations: i32,
+    state: &mut OptimalState<'a>,
+    mut ran_state: Option<&mut RanState>,
+    scratch: &mut OptimalScratch<'a>,
+) {
+    // The stores all refer to the input.
+    let input = scratch.currentstore.data;
+    if state.randomize {
+        match ran_state.as_deref_mut() {
+            Some(ran_state) => state.randomize_stats(ran_state),
+            None => return,
+        }
+    }
+    // Repeat statistics with each time the cost model from the previous stat run.
+    while state.iteration < numiterations {
+        let i = state.iteration;
+        state.iteration += 1;
+        let currentstore = &mut scratch.currentstore;
+        currentstore.clear();
+        lz77_optimal_run(
+            s, input, instart, inend, &mut scratch.path, &mut scratch.length_array,
+            &state.stats, currentstore, &mut *scratch.finder, &mut scratch.costs,
+        );
+        let cost = calculate_block_size(currentstore, 0, currentstore.size(), 2);
+        if s.options.verbose_more || (s.options.verbose && cost < state.bestcost) {
+            eprintln!("Iteration {}: {} bit", i, cost as i32);
+        }
+        if cost < state.bestcost {
+            // Copy to the output store.
+            state.beststore = currentstore.clone();
+            state.beststats = state.stats.clone();
+            state.bestcost = cost;
+        }
+        let laststats = state.stats.clone();
+        clear_stat_freqs(&mut state.stats);
+        get_statistics(currentstore, &mut state.stats);
+        if state.lastrandomstep != -1 {
+            // This makes it converge slower but better. Do it only once the
+            // randomness kicks in so that if the user does few iterations, it gives
+            // a better result sooner.
+            let current = state.stats.clone();
+            add_weighed_stat_freqs(&current, 1.0, &laststats, 0.5, &mut state.stats);
+            calculate_statistics(&mut state.stats);
+        }
+        if i > 5 && cost == state.lastcost {
+            state.randomize = true;
+        }
+        state.lastcost = cost;
+        if state.randomize {
+            match ran_state.as_deref_mut() {
+                Some(ran_state) => state.randomize_stats(ran_state),
+                None => return,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::Options;
+    use crate::types::{Hash, Options};
     
     #[test]
     fn test_get_match() {
@@ -378,6 +878,21 @@ mod tests {
         assert_eq!(len, 5); // "hello" matches
     }
     
+    #[test]
+    fn test_get_match_every_length() {
+        // Match of each length up to 40 ending in a mismatch or at end, to hit
+        // every byte of the words and of the tail.
+        for len in 0..40 {
+            for limit in len..42 {
+                let mut data = vec![7u8; 100];
+                data[50 + len] = 8;
+                assert_eq!(get_match(&data, 50, 10, 50 + limit), len, "len {} limit {}", len, limit);
+            }
+        }
+        assert_eq!(get_match(b"abab", 2, 0, 4), 2);
+        assert_eq!(get_match(b"abc", 3, 0, 3), 0);
+    }
+    
     #[test]
     fn test_store_lit_len_dist_literal() {
         let data = b"test";
@@ -410,7 +925,7 @@ mod tests {
         let mut store = LZ77Store::new(data);
         let mut hash = Hash::new(WINDOW_SIZE);
         
-        lz77_greedy(&mut state, data, 0, data.len(), &mut store, &mut hash);
+        lz77_greedy(&mut state, data, 0, data.len(), &mut store, &mut hash).unwrap();
         
         // Should have compressed the repeated 'a's
         assert!(store.size() > 0);
@@ -425,7 +940,7 @@ mod tests {
         let mut store = LZ77Store::new(data);
         let mut hash = Hash::new(WINDOW_SIZE);
         
-        lz77_greedy(&mut state, data, 0, data.len(), &mut store, &mut hash);
+        lz77_greedy(&mut state, data, 0, data.len(), &mut store, &mut hash).unwrap();
         
         // Should find the repeated "hello"
         assert!(store.size() > 0);
@@ -434,4 +949,204 @@ mod tests {
         let has_backreference = store.dists.iter().any(|&d| d > 0);
         assert!(has_backreference, "Should find repeated 'hello'");
     }
+
+    fn assert_store_covers(store: &LZ77Store, data: &[u8]) {
+        let mut pos = 0;
+        for i in 0..store.size() {
+            assert_eq!(store.pos[i], pos);
+            if store.dists[i] == 0 {
+                assert_eq!(store.litlens[i], data[pos] as u16);
+                pos += 1;
+            } else {
+                let dist = store.dists[i] as usize;
+                for k in 0..store.litlens[i] as usize {
+                    assert_eq!(data[pos + k], data[pos + k - dist]);
+                }
+                pos += store.litlens[i] as usize;
+            }
+        }
+        assert_eq!(pos, data.len());
+    }
+    
+    #[test]
+    fn test_trace_backwards() {
+        // Reach byte 1 with a literal, byte 4 with a length 3 match, byte 5 with a literal.
+        let length_array = [0u16, 1, 1, 2, 3, 1];
+        let mut path = Vec::new();
+        trace_backwards(5, &length_array, &mut path);
+        assert_eq!(path, vec![1, 3, 1]);
+    }
+    
+    #[test]
+    fn test_cost_fixed_matches_fixed_tree() {
+        assert_eq!(FixedCostModel.cost(b'a' as usize, 0), 8.0);
+        assert_eq!(FixedCostModel.cost(200, 0), 9.0);
+        // Length 3 (symbol 257, 7 bits) at distance 1 (5 bits).
+        assert_eq!(FixedCostModel.cost(3, 1), 12.0);
+        assert_eq!(FixedCostModel.min_cost(), 12.0);
+    }
+    
+    /// Fixed tree costs, with distances beyond 4096 priced out.
+    struct NearModel;
+    
+    impl CostModel for NearModel {
+        fn cost(&self, litlen: usize, dist: usize) -> f64 {
+            FixedCostModel.cost(litlen, dist) + if dist > 4096 { 1e6 } else { 0.0 }
+        }
+    }
+    
+    #[test]
+    fn test_custom_cost_model() {
+        // Random letters repeated once, at distance 5000.
+        let mut data = Vec::new();
+        let mut x: u32 = 3;
+        for _ in 0..5000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push(b'a' + (x >> 16) as u8 % 26);
+        }
+        data.extend_from_within(..5000);
+        let opts = Options::default();
+        
+        let mut s = BlockState::new(&opts, 0, data.len(), true);
+        let mut fixed = LZ77Store::new(&data);
+        lz77_optimal_with_cost_model(&mut s, &data, 0, data.len(), &FixedCostModel, &mut fixed).unwrap();
+        assert!(fixed.dists.iter().any(|&d| d as usize > 4096));
+        
+        let mut s = BlockState::new(&opts, 0, data.len(), true);
+        let mut near = LZ77Store::new(&data);
+        lz77_optimal_with_cost_model(&mut s, &data, 0, data.len(), &NearModel, &mut near).unwrap();
+        assert!(near.dists.iter().all(|&d| d as usize <= 4096));
+        assert_eq!(crate::block::lz77_get_byte_range(&near, 0, near.size()), data.len());
+    }
+    
+    #[test]
+    fn test_lz77_optimal_covers_input() {
+        let data = b"the quick brown fox jumps over the lazy dog, the quick brown fox again";
+        let opts = Options::default();
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut store = LZ77Store::new(data);
+        
+        lz77_optimal(&mut state, data, 0, data.len(), opts.numiterations, &mut store).unwrap();
+        
+        assert_store_covers(&store, data);
+        assert!(store.dists.iter().any(|&d| d > 0));
+    }
+    
+    #[test]
+    fn test_lz77_optimal_not_worse_than_greedy() {
+        let mut data = Vec::new();
+        for i in 0..2000u32 {
+            data.extend_from_slice(format!("{} ", (i * 7919) % 97).as_bytes());
+        }
+        let opts = Options::default();
+        
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut greedy = LZ77Store::new(&data);
+        let mut hash = Hash::new(WINDOW_SIZE);
+        lz77_greedy(&mut state, &data, 0, data.len(), &mut greedy, &mut hash).unwrap();
+        
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut optimal = LZ77Store::new(&data);
+        lz77_optimal(&mut state, &data, 0, data.len(), 5, &mut optimal).unwrap();
+        
+        assert_store_covers(&optimal, &data);
+        let greedy_cost = calculate_block_size(&greedy, 0, greedy.size(), 2);
+        let optimal_cost = calculate_block_size(&optimal, 0, optimal.size(), 2);
+        assert!(optimal_cost <= greedy_cost);
+    }
+
+    fn optimal_with(data: &[u8], seed: u64, trials: usize) -> LZ77Store<'_> {
+        let opts = Options { seed, trials, ..Options::default() };
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut store = LZ77Store::new(data);
+        lz77_optimal(&mut state, data, 0, data.len(), 20, &mut store).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_lz77_optimal_seed_and_trials() {
+        let mut data = Vec::new();
+        let mut x: u32 = 11;
+        for _ in 0..1000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.extend_from_slice([&b"ab"[..], b"ba ", b"abba", b"b"][(x >> 16) as usize % 4]);
+        }
+        let cost = |store: &LZ77Store| calculate_block_size(store, 0, store.size(), 2);
+
+        // Reproducible for a seed, and the randomization depends on it.
+        let a = optimal_with(&data, 5, 1);
+        let again = optimal_with(&data, 5, 1);
+        assert_eq!((&a.litlens, &a.dists), (&again.litlens, &again.dists));
+        let costs: Vec<f64> = (6..10).map(|seed| cost(&optimal_with(&data, seed, 1))).collect();
+        assert!(costs.iter().any(|&c| c != cost(&a)), "all seeds gave the same result");
+
+        // The first trial uses the seed itself, so more trials are never worse.
+        let best = optimal_with(&data, 5, 4);
+        assert_store_covers(&best, &data);
+        assert!(cost(&best) <= cost(&a));
+        let again = optimal_with(&data, 5, 4);
+        assert_eq!((&best.litlens, &best.dists), (&again.litlens, &again.dists));
+    }
+    
+    #[test]
+    fn test_lz77_optimal_fixed_not_worse_than_greedy() {
+        let data = b"fixed tree, fixed tree, fixed tree and some more text for the fixed tree";
+        let opts = Options::default();
+        
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut greedy = LZ77Store::new(data);
+        let mut hash = Hash::new(WINDOW_SIZE);
+        lz77_greedy(&mut state, data, 0, data.len(), &mut greedy, &mut hash).unwrap();
+        
+        let mut state = BlockState::new(&opts, 0, data.len(), true);
+        let mut fixed = LZ77Store::new(data);
+        lz77_optimal_fixed(&mut state, data, 0, data.len(), &mut fixed).unwrap();
+        
+        assert_store_covers(&fixed, data);
+        let greedy_cost = calculate_block_size(&greedy, 0, greedy.size(), 1);
+        let fixed_cost = calculate_block_size(&fixed, 0, fixed.size(), 1);
+        assert!(fixed_cost <= greedy_cost);
+    }
+    
+    #[test]
+    fn test_invalid_ranges() {
+        let data = b"some input to parse";
+        let opts = Options::default();
+        let mut store = LZ77Store::new(data);
+        let mut hash = Hash::new(WINDOW_SIZE);
+        
+        // Out of bounds of the input.
+        let mut state = BlockState::new(&opts, 0, data.len() + 1, false);
+        let result = lz77_greedy(&mut state, data, 0, data.len() + 1, &mut store, &mut hash);
+        assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+        let result = lz77_optimal(&mut state, data, 5, 4, 1, &mut store);
+        assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+        
+        // Not covered by the longest match cache.
+        let mut state = BlockState::new(&opts, 4, 8, true);
+        let result = lz77_optimal(&mut state, data, 0, data.len(), 1, &mut store);
+        assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+        let result = lz77_optimal_fixed(&mut state, data, 4, 9, &mut store);
+        assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+        assert_eq!(store.size(), 0);
+        
+        lz77_optimal_fixed(&mut state, data, 4, 8, &mut store).unwrap();
+        assert_eq!(store.size(), 4);
+    }
+
+    #[test]
+    fn test_invalid_numiterations() {
+        let data = b"some input to parse";
+        let opts = Options::default();
+        let mut store = LZ77Store::new(data);
+        let mut state = BlockState::new(&opts, 0, data.len(), false);
+        lz77_greedy(&mut state, data, 0, data.len(), &mut store, &mut Hash::new(WINDOW_SIZE)).unwrap();
+        let size = store.size();
+        for numiterations in [0, -5] {
+            let mut state = BlockState::new(&opts, 0, data.len(), true);
+            let result = lz77_optimal(&mut state, data, 0, data.len(), numiterations, &mut store);
+            assert!(matches!(result, Err(ZopfliError::InvalidOptions(_))));
+            assert_eq!(store.size(), size);
+        }
+    }
 }
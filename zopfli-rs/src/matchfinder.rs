@@ -0,0 +1,473 @@
+// Copyright Anysphere Inc.
+// Match finders used by the LZ77 parsers
+
+use crate::types::{Hash, MIN_MATCH, MAX_MATCH, WINDOW_SIZE, WINDOW_MASK, MAX_CHAIN_HITS};
+use crate::hash::{update_hash, warmup_hash, reset_hash, update_same};
+use crate::lz77::get_match;
+
+/// Finds earlier occurrences of the data at a position for the LZ77 parsers.
+/// The parsers call reset before a block, then update for every position in
+/// order, starting at the start of the window before the block. find is only
+/// called for the position of the last update, and not at all for positions
+/// the longest match cache has results for.
+pub trait MatchFinder {
+    /// Forgets all positions and prepares for updates starting at windowstart.
+    /// end is the end of the block, no data at or after it is used.
+    fn reset(&mut self, array: &[u8], windowstart: usize, end: usize);
+
+    /// Adds position pos, which must follow the previously added one.
+    fn update(&mut self, array: &[u8], pos: usize, end: usize);
+
+    /// Finds the longest match for pos of at most limit bytes, which is at least
+    /// MIN_MATCH and does not reach past size. If given, sublen[k] is set to the
+    /// distance of the closest match of at least k bytes, for every k from 2 up
+    /// to the found length. Matches shorter than MIN_MATCH need not be found.
+    /// Sets length to 1 and distance to 0 if there is no match.
+    #[allow(clippy::too_many_arguments)]
+    fn find(
+        &mut self,
+        array: &[u8],
+        pos: usize,
+        size: usize,
+        limit: usize,
+        sublen: Option<&mut [u16]>,
+        distance: &mut u16,
+        length: &mut u16,
+    );
+
+    /// Returns the amount of repetitions of the byte at pos directly after it,
+    /// for a position in the window of the last update.
+    fn same(&self, pos: usize) -> u16;
+}
+
+/// Which MatchFinder the compression uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchFinderKind {
+    /// Dual hash chains as in the C code, see Hash. Walks at most
+    /// MAX_CHAIN_HITS earlier occurrences per position, so on very repetitive
+    /// input it may miss the closest distances of some lengths.
+    #[default]
+    HashChain,
+    /// Binary tree, see BinaryTree. Also walks at most MAX_CHAIN_HITS earlier
+    /// positions per insertion, so sublen is not exhaustive either: the closest
+    /// distances are found for every length up to where the walk stops. Much
+    /// faster than HashChain on input with a small alphabet, and about as fast
+    /// on typical input, see benches/match_finders.rs.
+    BinaryTree,
+}
+
+impl MatchFinderKind {
+    /// Returns a new match finder of this kind.
+    pub fn create(self) -> Box<dyn MatchFinder> {
+        match self {
+            MatchFinderKind::HashChain => Box::new(Hash::new(WINDOW_SIZE)),
+            MatchFinderKind::BinaryTree => Box::new(BinaryTree::new()),
+        }
+    }
+}
+
+impl MatchFinder for Hash {
+    fn reset(&mut self, array: &[u8], windowstart: usize, end: usize) {
+        reset_hash(self);
+        warmup_hash(array, windowstart, end, self);
+    }
+
+    fn update(&mut self, array: &[u8], pos: usize, end: usize) {
+        update_hash(array, pos, end, self);
+    }
+
+    fn find(
+        &mut self,
+        array: &[u8],
+        pos: usize,
+        size: usize,
+        limit: usize,
+        mut sublen: Option<&mut [u16]>,
+        distance: &mut u16,
+        length: &mut u16,
+    ) {
+        let h = self;
+        let hpos = (pos & WINDOW_MASK) as u16;
+        let mut bestdist = 0u16;
+        let mut bestlength = 1u16;
+
+        let mut hhead = &h.head;
+        let mut hprev = &h.prev;
+        let mut hhashval = &h.hashval;
+        let mut hval = h.val;
+
+        debug_assert!((hval as usize) < 65536);
+
+        let mut pp = hhead[hval as usize];
+        if pp < 0 {
+            *length = bestlength;
+            *distance = bestdist;
+            return;
+        }
+
+        let mut p = hprev[pp as usize];
+        let mut dist = if p < hpos {
+            hpos - p
+        } else {
+            (WINDOW_SIZE as u16 - p) + hpos
+        };
+
+        let mut chain_counter = MAX_CHAIN_HITS;
+
+        // Go through all distances
+        while (dist as usize) < WINDOW_SIZE {
+            debug_assert!((p as usize) < WINDOW_SIZE);
+            debug_assert_eq!(p, hprev[pp as usize]);
+            debug_assert_eq!(hhashval[p as usize], hval);
+
+            if dist > 0 {
+                debug_assert!(pos < size);
+                debug_assert!((dist as usize) <= pos);
+
+                let scan_pos = pos;
+                let match_pos = pos - dist as usize;
+
+                // Testing the byte at position bestlength first, goes slightly faster
+                let mut currentlength = 0usize;
+                if pos + bestlength as usize >= size ||
+                   array[scan_pos + bestlength as usize] == array[match_pos + bestlength as usize] {
+
+                    let same0 = h.same[pos & WINDOW_MASK];
+                    if same0 > 2 && array[scan_pos] == array[match_pos] {
+                        let same1 = h.same[(pos - dist as usize) & WINDOW_MASK];
+                        let same = if same0 < same1 { same0 } else { same1 };
+                        let same = if same as usize > limit { limit as u16 } else { same };
+
+                        currentlength = same as usize;
+                    }
+
+                    let remaining = get_match(array, scan_pos + currentlength, match_pos + currentlength, scan_pos + limit);
+                    currentlength += remaining;
+                }
+
+                if currentlength > bestlength as usize {
+                    if let Some(ref mut sublen_arr) = sublen {
+                        for j in (bestlength as usize + 1)..=currentlength {
+                            sublen_arr[j] = dist;
+                        }
+                    }
+                    bestdist = dist;
+                    bestlength = currentlength as u16;
+                    if currentlength >= limit {
+                        break;
+                    }
+                }
+            }
+
+            // Switch to the other hash once this will be more efficient
+            if !std::ptr::eq(hhead, &h.head2) && bestlength >= h.same[hpos as usize] &&
+               h.val2 == h.hashval2[p as usize] {
+                hhead = &h.head2;
+                hprev = &h.prev2;
+                hhashval = &h.hashval2;
+                hval = h.val2;
+            }
+
+            pp = p as i32;
+            p = hprev[p as usize];
+            if p == pp as u16 {
+                break; // Uninited prev value
+            }
+
+            let new_dist = if p < (pp as u16) {
+                (pp as u16) - p
+            } else {
+                (WINDOW_SIZE as u16 - p) + (pp as u16)
+            };
+            dist += new_dist;
+
+            chain_counter -= 1;
+            if chain_counter == 0 {
+                break;
+            }
+        }
+
+        *distance = bestdist;
+        *length = bestlength;
+    }
+
+    fn same(&self, pos: usize) -> u16 {
+        self.same[pos & WINDOW_MASK]
+    }
+}
+
+/// Amount of bits of the hash selecting the tree of a position.
+const BT_HASH_BITS: u32 = 16;
+
+/// Marks a missing position in BinaryTree.
+const BT_NONE: usize = usize::MAX;
+
+/// Match finder keeping, like the bt4 finder of LZMA, a binary search tree of
+/// the window positions ordered by the data following them. Positions are
+/// spread over several trees by a hash of their first MIN_MATCH bytes, since
+/// deflate matches start at 3 rather than 4 bytes. Each position is inserted
+/// at the root, so going down the tree only meets older positions: searching
+/// while inserting meets the closest position for every match length, and
+/// runs of repeated data end the search right at the root. Like the hash
+/// chains, the walk down the tree stops after MAX_CHAIN_HITS positions, after
+/// which longer matches further down are not found.
+/// Positions are only inserted once find needs them, so passes in which the
+/// longest match cache answers every search do not build the trees at all.
+#[derive(Debug)]
+pub struct BinaryTree {
+    /// Hash of the first MIN_MATCH bytes to the root of its tree, the most
+    /// recent position with that hash.
+    head: Vec<usize>,
+
+    /// Per window position, the roots of its subtrees of positions with
+    /// smaller and larger data, at index 2 * hpos and 2 * hpos + 1.
+    son: Vec<usize>,
+
+    /// Amount of repetitions of same byte after this
+    same: Vec<u16>,
+
+    /// Next position to insert, and the end of the block.
+    next: usize,
+    end: usize,
+
+    /// Length and distance of each longer match found when inserting next - 1,
+    /// in the order found.
+    matches: Vec<(u16, u16)>,
+}
+
+impl BinaryTree {
+    /// Creates an empty match finder. reset must be called before the first
+    /// find of each block.
+    pub fn new() -> Self {
+        BinaryTree {
+            head: vec![BT_NONE; 1 << BT_HASH_BITS],
+            son: vec![BT_NONE; 2 * WINDOW_SIZE],
+            same: vec![0; WINDOW_SIZE],
+            next: 0,
+            end: 0,
+            matches: Vec::new(),
+        }
+    }
+
+    /// Inserts pos into its tree, recording the matches met on the way.
+    fn insert(&mut self, array: &[u8], pos: usize) {
+        let end = self.end;
+        let hpos = pos & WINDOW_MASK;
+        self.matches.clear();
+        if pos + MIN_MATCH > end {
+            // Too close to the end to start a match, or to be matched.
+            self.son[2 * hpos] = BT_NONE;
+            self.son[2 * hpos + 1] = BT_NONE;
+            return;
+        }
+
+        let limit = MAX_MATCH.min(end - pos);
+        let h = bt_hash(array, pos);
+        let mut cur = self.head[h];
+        self.head[h] = pos;
+
+        // Slots receiving the next position smaller and larger than pos, and the
+        // amount of bytes known to match on each side.
+        let mut smaller = 2 * hpos;
+        let mut larger = 2 * hpos + 1;
+        let mut smallerlen = 0;
+        let mut largerlen = 0;
+        let mut bestlength = 1;
+        let mut counter = MAX_CHAIN_HITS;
+
+        loop {
+            if cur == BT_NONE || pos - cur >= WINDOW_SIZE || counter == 0 {
+                self.son[smaller] = BT_NONE;
+                self.son[larger] = BT_NONE;
+                break;
+            }
+            counter -= 1;
+
+            let pair = 2 * (cur & WINDOW_MASK);
+            let known = smallerlen.min(largerlen);
+            let len = known + get_match(array, pos + known, cur + known, pos + limit);
+            if len > bestlength {
+                self.matches.push((len as u16, (pos - cur) as u16));
+                bestlength = len;
+                if len == limit {
+                    // Equal as far as can be compared: cur is replaced by pos.
+                    self.son[smaller] = self.son[pair];
+                    self.son[larger] = self.son[pair + 1];
+                    break;
+                }
+            }
+
+            if array[cur + len] < array[pos + len] {
+                self.son[smaller] = cur;
+                smaller = pair + 1;
+                cur = self.son[pair + 1];
+                smallerlen = len;
+            } else {
+                self.son[larger] = cur;
+                larger = pair;
+                cur = self.son[pair];
+                largerlen = len;
+            }
+        }
+    }
+}
+
+impl Default for BinaryTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the tree of the MIN_MATCH bytes at pos.
+fn bt_hash(array: &[u8], pos: usize) -> usize {
+    let v = (array[pos] as u32) << 16 | (array[pos + 1] as u32) << 8 | array[pos + 2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - BT_HASH_BITS)) as usize
+}
+
+impl MatchFinder for BinaryTree {
+    fn reset(&mut self, _array: &[u8], windowstart: usize, end: usize) {
+        self.head.fill(BT_NONE);
+        self.same.fill(0);
+        self.next = windowstart;
+        self.end = end;
+        self.matches.clear();
+    }
+
+    fn update(&mut self, array: &[u8], pos: usize, end: usize) {
+        debug_assert_eq!(end, self.end);
+        update_same(array, pos, end, &mut self.same);
+    }
+
+    fn find(
+        &mut self,
+        array: &[u8],
+        pos: usize,
+        _size: usize,
+        limit: usize,
+        mut sublen: Option<&mut [u16]>,
+        distance: &mut u16,
+        length: &mut u16,
+    ) {
+        // Positions older than the window can not be matched from pos on.
+        self.next = self.next.max((pos + 1).saturating_sub(WINDOW_SIZE));
+        while self.next <= pos {
+            self.insert(array, self.next);
+            self.next += 1;
+        }
+
+        let mut bestlength = 1;
+        let mut bestdist = 0;
+        for &(len, dist) in &self.matches {
+            let len = (len as usize).min(limit);
+            if let Some(ref mut sublen) = sublen {
+                for j in (bestlength + 1)..=len {
+                    sublen[j] = dist;
+                }
+            }
+            bestlength = len;
+            bestdist = dist;
+            if len == limit {
+                break;
+            }
+        }
+        *distance = bestdist;
+        *length = bestlength as u16;
+    }
+
+    fn same(&self, pos: usize) -> u16 {
+        self.same[pos & WINDOW_MASK]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(size: usize, alphabet: u8) -> Vec<u8> {
+        let mut data = Vec::with_capacity(size);
+        let mut x: u32 = 11;
+        for _ in 0..size {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push(b'a' + (x >> 16) as u8 % alphabet);
+        }
+        data
+    }
+
+    /// Returns the closest distance of a match of at least k bytes for every k,
+    /// by trying all distances.
+    fn brute_force_sublen(data: &[u8], pos: usize, end: usize) -> Vec<u16> {
+        let limit = MAX_MATCH.min(end - pos);
+        let mut sublen = vec![0u16; limit + 1];
+        let mut bestlength = 1;
+        for dist in 1..WINDOW_SIZE.min(pos + 1) {
+            let len = get_match(data, pos, pos - dist, pos + limit);
+            if len > bestlength {
+                sublen[bestlength + 1..=len].fill(dist as u16);
+                bestlength = len;
+            }
+        }
+        sublen.truncate(bestlength + 1);
+        sublen
+    }
+
+    /// Checks the matches of the binary tree against brute force for the
+    /// positions from start on.
+    fn check_binary_tree(data: &[u8], start: usize) {
+        let mut bt = BinaryTree::new();
+        bt.reset(data, 0, data.len());
+        let mut sublen = [0u16; MAX_MATCH + 1];
+        for pos in 0..data.len() {
+            bt.update(data, pos, data.len());
+            if pos < start || pos + MIN_MATCH > data.len() {
+                continue;
+            }
+            let (mut dist, mut len) = (0, 0);
+            bt.find(data, pos, data.len(), MAX_MATCH, Some(&mut sublen), &mut dist, &mut len);
+            let expected = brute_force_sublen(data, pos, data.len());
+            if expected.len() - 1 < MIN_MATCH {
+                // Matches shorter than MIN_MATCH are not looked for.
+                assert!((len as usize) < MIN_MATCH, "pos {}", pos);
+                continue;
+            }
+            assert_eq!(len as usize, expected.len() - 1, "pos {}", pos);
+            assert_eq!(&sublen[MIN_MATCH..=len as usize], &expected[MIN_MATCH..], "pos {}", pos);
+            assert_eq!(dist, expected[len as usize]);
+        }
+    }
+
+    #[test]
+    fn test_binary_tree_finds_closest_matches() {
+        check_binary_tree(&test_data(3000, 3), 0);
+        check_binary_tree(&[b'a'; 1000], 0);
+    }
+
+    #[test]
+    fn test_binary_tree_window() {
+        // Distances of WINDOW_SIZE and more are not used.
+        let mut data = test_data(WINDOW_SIZE + 300, 4);
+        data.extend_from_within(..300);
+        check_binary_tree(&data, WINDOW_SIZE);
+    }
+
+    #[test]
+    fn test_finders_agree_on_longest_match() {
+        let data = test_data(5000, 4);
+        let mut hash = Hash::new(WINDOW_SIZE);
+        let mut bt = BinaryTree::new();
+        hash.reset(&data, 0, data.len());
+        bt.reset(&data, 0, data.len());
+        for pos in 0..data.len() - MIN_MATCH {
+            hash.update(&data, pos, data.len());
+            bt.update(&data, pos, data.len());
+            assert_eq!(hash.same(pos), bt.same(pos));
+            let limit = MAX_MATCH.min(data.len() - pos);
+            let (mut hdist, mut hlen) = (0, 0);
+            let (mut bdist, mut blen) = (0, 0);
+            hash.find(&data, pos, data.len(), limit, None, &mut hdist, &mut hlen);
+            bt.find(&data, pos, data.len(), limit, None, &mut bdist, &mut blen);
+            if (hlen as usize) >= MIN_MATCH {
+                assert_eq!((hlen, hdist), (blen, bdist), "pos {}", pos);
+            }
+        }
+    }
+}
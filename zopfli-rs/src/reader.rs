@@ -0,0 +1,259 @@
+// Copyright Anysphere Inc.
+// Streaming compressors implementing std::io::Read
+
+use std::io::{self, Read};
+
+use crate::types::Options;
+use crate::encoder::{MasterBlockCompressor, Trailer};
+use crate::error::ZopfliError;
+use crate::gzip::{write_gzip_header, GzipHeader};
+use crate::zlib::{adler32, write_zlib_header};
+
+/// Size of the pieces read from the source.
+const READ_CHUNK_SIZE: usize = 65536;
+
+/// Reads uncompressed data from the inner reader and yields it as a raw deflate
+/// stream. The source is read lazily, one master block of MASTER_BLOCK_SIZE
+/// bytes at a time, and each master block is compressed with the preceding
+/// WINDOW_SIZE bytes as dictionary, exactly like deflate does for in-memory
+/// input. Master blocks are compressed one after the other, see
+/// Options::threads.
+///
+/// Errors other than those of the inner reader are returned by read as an
+/// io::Error wrapping the ZopfliError, which io::Error::get_ref can downcast.
+pub struct DeflateReader<R: Read> {
+    inner: R,
+    compressor: MasterBlockCompressor,
+    /// Buffer the source is read into, of READ_CHUNK_SIZE bytes.
+    chunk: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> DeflateReader<R> {
+    /// Creates a reader compressing the data read from inner.
+    pub fn new(options: &Options, inner: R) -> Result<Self, ZopfliError> {
+        Self::with_prefix(options, inner, &[], Vec::new(), Trailer::None)
+    }
+
+    /// Creates a reader whose matches may refer back into the preset
+    /// dictionary. The decoder must be given the same dictionary.
+    pub fn with_dictionary(options: &Options, inner: R, dictionary: &[u8]) -> Result<Self, ZopfliError> {
+        Self::with_prefix(options, inner, dictionary, Vec::new(), Trailer::None)
+    }
+
+    /// Creates a reader that yields prefix, a container header, before the
+    /// deflate stream and trailer after it.
+    fn with_prefix(
+        options: &Options,
+        inner: R,
+        dictionary: &[u8],
+        prefix: Vec<u8>,
+        trailer: Trailer,
+    ) -> Result<Self, ZopfliError> {
+        Ok(DeflateReader {
+            inner,
+            compressor: MasterBlockCompressor::new(options, dictionary, prefix, trailer)?,
+            chunk: vec![0; READ_CHUNK_SIZE],
+            eof: false,
+        })
+    }
+
+    /// Returns a reference to the inner reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Reads from the source until a master block followed by more input is
+    /// available, or the source ends, and compresses it.
+    fn compress_next(&mut self) -> Result<(), ZopfliError> {
+        while !self.eof && !self.compressor.compress_full_block()? {
+            match self.inner.read(&mut self.chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.compressor.push(&self.chunk[..n])?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.eof && !self.compressor.compress_full_block()? {
+            self.compressor.compress_final_block()?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DeflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.compressor.output().is_empty() {
+            if self.compressor.is_finished() {
+                return Ok(0);
+            }
+            self.compress_next()?;
+        }
+        let output = self.compressor.output();
+        let n = output.len().min(buf.len());
+        buf[..n].copy_from_slice(&output[..n]);
+        self.compressor.consume(n);
+        Ok(n)
+    }
+}
+
+/// Yields the data read from the inner reader compressed in the gzip format,
+/// RFC 1952. See DeflateReader for the buffering behavior and errors.
+pub struct GzipReader<R: Read> {
+    deflate: DeflateReader<R>,
+}
+
+impl<R: Read> GzipReader<R> {
+    /// Creates a reader yielding a gzip member with a default header.
+    pub fn new(options: &Options, inner: R) -> Result<Self, ZopfliError> {
+        Self::with_header(options, &GzipHeader::default(), inner)
+    }
+
+    /// Creates a reader yielding a gzip member with the given header.
+    pub fn with_header(options: &Options, header: &GzipHeader, inner: R) -> Result<Self, ZopfliError> {
+        let mut prefix = Vec::new();
+        write_gzip_header(header, &mut prefix)?;
+        let trailer = Trailer::Gzip { crc: 0, insize: 0 };
+        Ok(GzipReader { deflate: DeflateReader::with_prefix(options, inner, &[], prefix, trailer)? })
+    }
+
+    /// Returns a reference to the inner reader.
+    pub fn get_ref(&self) -> &R {
+        self.deflate.get_ref()
+    }
+}
+
+impl<R: Read> Read for GzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.deflate.read(buf)
+    }
+}
+
+/// Yields the data read from the inner reader compressed in the zlib format,
+/// RFC 1950. See DeflateReader for the buffering behavior and errors.
+pub struct ZlibReader<R: Read> {
+    deflate: DeflateReader<R>,
+}
+
+impl<R: Read> ZlibReader<R> {
+    /// Creates a reader yielding a zlib stream.
+    pub fn new(options: &Options, inner: R) -> Result<Self, ZopfliError> {
+        Self::with_prefix(options, inner, &[], None)
+    }
+
+    /// Creates a reader yielding a zlib stream that uses the preset dictionary.
+    /// The decompressor must be given the same dictionary.
+    pub fn with_dictionary(options: &Options, inner: R, dictionary: &[u8]) -> Result<Self, ZopfliError> {
+        Self::with_prefix(options, inner, dictionary, Some(adler32(dictionary)))
+    }
+
+    fn with_prefix(options: &Options, inner: R, dictionary: &[u8], dictid: Option<u32>) -> Result<Self, ZopfliError> {
+        let mut prefix = Vec::new();
+        write_zlib_header(dictid, &mut prefix);
+        let trailer = Trailer::Zlib { adler: 1 };
+        Ok(ZlibReader { deflate: DeflateReader::with_prefix(options, inner, dictionary, prefix, trailer)? })
+    }
+
+    /// Returns a reference to the inner reader.
+    pub fn get_ref(&self) -> &R {
+        self.deflate.get_ref()
+    }
+}
+
+impl<R: Read> Read for ZlibReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.deflate.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::deflate;
+    use crate::gzip::gzip_compress;
+    use crate::inflate::inflate;
+    use crate::zlib::{zlib_compress, zlib_compress_with_dictionary};
+
+    fn test_data(size: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(size);
+        let mut x: u32 = 7;
+        while data.len() < size {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.extend_from_slice(["red ", "green ", "blue ", "yellow "][(x >> 16) as usize % 4].as_bytes());
+        }
+        data.truncate(size);
+        data
+    }
+
+    fn fast_options() -> Options {
+        Options { numiterations: 1, ..Options::default() }
+    }
+
+    /// Source that returns at most 100 bytes per read, to exercise partial reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(100);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_matches_in_memory() {
+        let opts = fast_options();
+        let data = test_data(5000);
+
+        let mut out = Vec::new();
+        io::copy(&mut DeflateReader::new(&opts, Trickle(&data)).unwrap(), &mut out).unwrap();
+        assert_eq!(out, deflate(&opts, 2, &data).unwrap());
+
+        let mut out = Vec::new();
+        GzipReader::new(&opts, &data[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, gzip_compress(&opts, &data).unwrap());
+
+        let mut out = Vec::new();
+        ZlibReader::new(&opts, &data[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, zlib_compress(&opts, &data).unwrap());
+
+        let dictionary = b"red green blue yellow";
+        let mut out = Vec::new();
+        ZlibReader::with_dictionary(&opts, &data[..], dictionary).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, zlib_compress_with_dictionary(&opts, Some(dictionary), &data).unwrap());
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let opts = fast_options();
+        let mut out = Vec::new();
+        DeflateReader::new(&opts, &[][..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, deflate(&opts, 2, &[]).unwrap());
+    }
+
+    #[test]
+    fn test_small_reads_over_master_blocks() {
+        let opts = fast_options();
+        let data = test_data(20000);
+
+        let mut reader = GzipReader::new(&opts, Trickle(&data)).unwrap();
+        reader.deflate.compressor.master_block_size = 6000;
+        let mut out = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        // Reading after the end keeps returning 0.
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let n = out.len();
+        assert_eq!(&out[n - 8..n - 4], &crate::gzip::crc32(&data).to_le_bytes());
+        assert_eq!(&out[n - 4..], &(data.len() as u32).to_le_bytes());
+        assert_eq!(inflate(&out[10..n - 8]).unwrap(), data);
+    }
+}
@@ -1,5 +1,305 @@
 // Copyright Anysphere Inc.
-// Block splitting implementation (stub - will implement fully)
+// Block splitting implementation
 
-// Placeholder for now
+use crate::types::{LZ77Store, Options, BlockState, SplitCostContext, LARGE_FLOAT};
+use crate::block::calculate_block_size_auto_type;
+use crate::lz77::lz77_greedy;
+use crate::error::ZopfliError;
 
+/// Function of which the minimum is searched by find_minimum, with a context.
+type FindMinimumFun = fn(usize, &SplitCostContext) -> f64;
+
+/// Try to find minimum faster by recursively checking multiple points.
+const NUM: usize = 9; // Good value: 9.
+
+/// Returns estimated cost of a block in bits. It includes the size to encode the
+/// tree and the size to encode all literal, length and distance symbols and their
+/// extra bits.
+fn estimate_cost(lz77: &LZ77Store, lstart: usize, lend: usize) -> f64 {
+    calculate_block_size_auto_type(lz77, lstart, lend)
+}
+
+/// Gets the cost which is the sum of the cost of the left and the right section
+/// of the data.
+fn split_cost(i: usize, c: &SplitCostContext) -> f64 {
+    estimate_cost(c.lz77, c.start, i) + estimate_cost(c.lz77, i, c.end)
+}
+
+/// Finds minimum of function f(i) where i is in range start-end (excluding end).
+/// Returns the index of the minimum value and the value itself.
+fn find_minimum(f: FindMinimumFun, context: &SplitCostContext, mut start: usize, mut end: usize) -> (usize, f64) {
+    if end - start < 1024 {
+        let mut best = LARGE_FLOAT;
+        let mut result = start;
+        for i in start..end {
+            let v = f(i, context);
+            if v < best {
+                best = v;
+                result = i;
+            }
+        }
+        (result, best)
+    } else {
+        let mut p = [0usize; NUM];
+        let mut vp = [0f64; NUM];
+        let mut lastbest = LARGE_FLOAT;
+        let mut pos = start;
+
+        loop {
+            if end - start <= NUM {
+                break;
+            }
+
+            for (i, (pi, vpi)) in p.iter_mut().zip(vp.iter_mut()).enumerate() {
+                *pi = start + (i + 1) * ((end - start) / (NUM + 1));
+                *vpi = f(*pi, context);
+            }
+            let mut besti = 0;
+            let mut best = vp[0];
+            for (i, &v) in vp.iter().enumerate().skip(1) {
+                if v < best {
+                    best = v;
+                    besti = i;
+                }
+            }
+            if best > lastbest {
+                break;
+            }
+
+            start = if besti == 0 { start } else { p[besti - 1] };
+            end = if besti == NUM - 1 { end } else { p[besti + 1] };
+
+            pos = p[besti];
+            lastbest = best;
+        }
+        (pos, lastbest)
+    }
+}
+
+/// Inserts value into the sorted list of split points, keeping it sorted.
+fn add_sorted(value: usize, out: &mut Vec<usize>) {
+    let index = out.iter().position(|&v| v > value).unwrap_or(out.len());
+    out.insert(index, value);
+}
+
+/// Finds next block to try to split, the largest of the available ones.
+/// The largest is chosen to make sure that if only a limited amount of blocks is
+/// requested, their sizes are spread evenly.
+/// done indicates which blocks starting at that position are no longer
+/// splittable (splitting them increases rather than decreases cost).
+/// Returns the start and end of the block, or None if all blocks are done.
+fn find_largest_splittable_block(
+    lz77size: usize,
+    done: &[bool],
+    splitpoints: &[usize],
+) -> Option<(usize, usize)> {
+    let mut longest = 0;
+    let mut found = None;
+    let npoints = splitpoints.len();
+    for i in 0..=npoints {
+        let start = if i == 0 { 0 } else { splitpoints[i - 1] };
+        let end = if i == npoints { lz77size - 1 } else { splitpoints[i] };
+        if !done[start] && end - start > longest {
+            found = Some((start, end));
+            longest = end - start;
+        }
+    }
+    found
+}
+
+/// Prints the block split points as decimal and hex values in the terminal.
+fn print_block_split_points(lz77: &LZ77Store, lz77splitpoints: &[usize]) {
+    // The input is given as lz77 indices, but we want to see the uncompressed
+    // index values.
+    let splitpoints = lz77_split_points_to_bytes(lz77, lz77splitpoints, 0);
+
+    let decimal: Vec<String> = splitpoints.iter().map(|p| p.to_string()).collect();
+    let hex: Vec<String> = splitpoints.iter().map(|p| format!("{:x}", p)).collect();
+    eprintln!("block split points: {} (hex: {})", decimal.join(" "), hex.join(" "));
+}
+
+/// Converts split points given as LZ77 indices to positions in the uncompressed
+/// input, where the LZ77 data starts at byte position start.
+fn lz77_split_points_to_bytes(lz77: &LZ77Store, lz77splitpoints: &[usize], start: usize) -> Vec<usize> {
+    let mut splitpoints = Vec::with_capacity(lz77splitpoints.len());
+    let mut pos = start;
+    if !lz77splitpoints.is_empty() {
+        for i in 0..lz77.size() {
+            let length = if lz77.dists[i] == 0 { 1 } else { lz77.litlens[i] as usize };
+            if lz77splitpoints[splitpoints.len()] == i {
+                splitpoints.push(pos);
+                if splitpoints.len() == lz77splitpoints.len() {
+                    break;
+                }
+            }
+            pos += length;
+        }
+    }
+    debug_assert_eq!(splitpoints.len(), lz77splitpoints.len());
+    splitpoints
+}
+
+/// Does blocksplitting on LZ77 data.
+/// The output splitpoints are indices in the LZ77 data.
+/// maxblocks: set a limit to the amount of blocks. Set to 0 to mean no limit.
+pub fn block_split_lz77(options: &Options, lz77: &LZ77Store, maxblocks: usize) -> Vec<usize> {
+    let mut splitpoints = Vec::new();
+
+    if lz77.size() < 10 {
+        return splitpoints; // This code fails on tiny files.
+    }
+
+    let mut done = vec![false; lz77.size()];
+    let mut numblocks = 1;
+    let mut lstart = 0;
+    let mut lend = lz77.size();
+
+    loop {
+        if maxblocks > 0 && numblocks >= maxblocks {
+            break;
+        }
+
+        let c = SplitCostContext { lz77, start: lstart, end: lend };
+        debug_assert!(lstart < lend);
+        let (llpos, splitcost) = find_minimum(split_cost, &c, lstart + 1, lend);
+
+        debug_assert!(llpos > lstart);
+        debug_assert!(llpos < lend);
+
+        let origcost = estimate_cost(lz77, lstart, lend);
+
+        if splitcost > origcost || llpos == lstart + 1 || llpos == lend {
+            done[lstart] = true;
+        } else {
+            add_sorted(llpos, &mut splitpoints);
+            numblocks += 1;
+        }
+
+        match find_largest_splittable_block(lz77.size(), &done, &splitpoints) {
+            Some((start, end)) => {
+                lstart = start;
+                lend = end;
+            }
+            None => break, // No further split will probably reduce compression.
+        }
+
+        if lend - lstart < 10 {
+            break;
+        }
+    }
+
+    if options.verbose {
+        print_block_split_points(lz77, &splitpoints);
+    }
+
+    splitpoints
+}
+
+/// Does blocksplitting on uncompressed data.
+/// The output splitpoints are indices in the input array. The amount of blocks is
+/// the amount of splitpoints + 1. Returns no splitpoints if block splitting is
+/// disabled in the options.
+/// maxblocks: maximum amount of blocks to split into, or 0 for no limit
+/// Returns InvalidOptions if instart..inend is not a range of input.
+pub fn block_split(
+    options: &Options,
+    input: &[u8],
+    instart: usize,
+    inend: usize,
+    maxblocks: usize,
+) -> Result<Vec<usize>, ZopfliError> {
+    if !options.blocksplitting {
+        return Ok(Vec::new());
+    }
+
+    let mut s = BlockState::new(options, instart, inend, false);
+    let mut store = LZ77Store::new(input);
+    let mut finder = options.match_finder.create();
+
+    // Unintuitively, Using a simple LZ77 method here instead of lz77_optimal
+    // results in better blocks.
+    lz77_greedy(&mut s, input, instart, inend, &mut store, &mut *finder)?;
+
+    let lz77splitpoints = block_split_lz77(options, &store, maxblocks);
+
+    // Convert LZ77 positions to positions in the uncompressed input.
+    Ok(lz77_split_points_to_bytes(&store, &lz77splitpoints, instart))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_sorted() {
+        let mut points = Vec::new();
+        add_sorted(5, &mut points);
+        add_sorted(2, &mut points);
+        add_sorted(9, &mut points);
+        add_sorted(7, &mut points);
+        assert_eq!(points, vec![2, 5, 7, 9]);
+    }
+
+    #[test]
+    fn test_find_largest_splittable_block() {
+        let done = vec![false; 100];
+        assert_eq!(find_largest_splittable_block(100, &done, &[30, 40]), Some((40, 99)));
+
+        let mut done = vec![false; 100];
+        done[40] = true;
+        assert_eq!(find_largest_splittable_block(100, &done, &[30, 40]), Some((0, 30)));
+
+        let done = vec![true; 100];
+        assert_eq!(find_largest_splittable_block(100, &done, &[30, 40]), None);
+    }
+
+    #[test]
+    fn test_block_split_tiny_input() {
+        let opts = Options::default();
+        let data = b"abc";
+        assert!(block_split(&opts, data, 0, data.len(), 15).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_block_split_disabled() {
+        let opts = Options { blocksplitting: false, ..Options::default() };
+        let data = mixed_data();
+        assert!(block_split(&opts, &data, 0, data.len(), 15).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_block_split_mixed_content() {
+        let opts = Options::default();
+        let data = mixed_data();
+        let points = block_split(&opts, &data, 0, data.len(), opts.blocksplittingmax).unwrap();
+
+        assert!(!points.is_empty());
+        assert!(points.len() < opts.blocksplittingmax);
+        assert!(points.windows(2).all(|w| w[0] < w[1]));
+        assert!(points.iter().all(|&p| p > 0 && p < data.len()));
+        // The boundary between the two alphabets is found.
+        assert!(points.iter().any(|&p| p.abs_diff(5000) < 100));
+    }
+
+    #[test]
+    fn test_block_split_respects_maxblocks() {
+        let opts = Options::default();
+        let data = mixed_data();
+        let points = block_split(&opts, &data, 0, data.len(), 2).unwrap();
+        assert!(points.len() <= 1);
+    }
+
+    /// Two halves of pseudo-random letters drawn from disjoint alphabets, so that
+    /// each half prefers its own Huffman table.
+    fn mixed_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut x: u32 = 12345;
+        for alphabet in [b"abcd", b"wxyz"] {
+            for _ in 0..5000 {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                data.push(alphabet[(x >> 16) as usize % 4]);
+            }
+        }
+        data
+    }
+}
@@ -218,7 +218,7 @@ mod tests {
         // Test that symbol functions are consistent
         for len in MIN_MATCH..=MAX_MATCH {
             let symbol = get_length_symbol(len);
-            assert!(symbol >= 257 && symbol <= 285);
+            assert!((257..=285).contains(&symbol));
             
             let extra_bits = get_length_extra_bits(len);
             let extra_value = get_length_extra_bits_value(len);
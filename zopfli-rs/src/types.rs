@@ -1,6 +1,12 @@
 // Copyright Anysphere Inc.
 // Core type definitions for Zopfli compression
 
+use std::ops::Range;
+
+use crate::error::ZopfliError;
+use crate::lz77::store_lit_len_dist;
+use crate::matchfinder::MatchFinderKind;
+
 /// Number of distinct literal/length symbols in DEFLATE
 pub const NUM_LL: usize = 288;
 
@@ -50,14 +56,54 @@ pub struct Options {
     pub blocksplitting: bool,
     
     /// No longer used, left for compatibility.
+    #[deprecated(note = "has no effect, block splitting always happens before the LZ77 optimization")]
     pub blocksplittinglast: bool,
     
     /// Maximum amount of blocks to split into (0 for unlimited, but this can give
     /// extreme results that hurt compression on some files). Default value: 15.
     pub blocksplittingmax: usize,
+    
+    /// If true, the compression functions decode their own output and compare
+    /// it against the input, returning ZopfliError::VerificationFailed instead
+    /// of a corrupt stream on mismatch. The streaming encoders and readers
+    /// check each master block, and the gzip or zlib trailer after the last
+    /// one, before passing any of its output on.
+    pub verify: bool,
+    
+    /// Number of threads compressing concurrently. 0 and 1 both compress on
+    /// the calling thread. The output does not depend on it. The in-memory
+    /// functions such as deflate, gzip_compress and zlib_compress compress
+    /// several master blocks at once; the streaming encoders and readers
+    /// compress one master block at a time, as its input arrives, and only
+    /// spread the subblocks of that master block over the threads.
+    pub threads: usize,
+    
+    /// If true, uses only the greedy LZ77 parse instead of the iterative
+    /// optimization, and numiterations is unused. Much faster, but compresses
+    /// about as well as zlib rather than better.
+    pub greedy: bool,
+    
+    /// How the LZ77 parsers find matches. Default value: HashChain, which gives
+    /// the same output as the C code.
+    pub match_finder: MatchFinderKind,
+    
+    /// Seed of the random state the iterative optimization uses to perturb the
+    /// statistics once it stops improving. The output is reproducible for a
+    /// given seed. Default value: 0, which gives the same output as the C code.
+    pub seed: u64,
+    
+    /// Number of independently seeded optimizations run per block, of which the
+    /// smallest result is kept. The first one uses seed itself. The trials only
+    /// differ once the randomization starts, so this needs numiterations well
+    /// above 5 to help. Default value: 1.
+    pub trials: usize,
 }
 
 impl Default for Options {
+    /// The default preset, with the same settings as the C code: 15 iterations,
+    /// block splitting into at most 15 blocks, and the hash chain match finder.
+    /// The other presets are variations of it.
+    #[allow(deprecated)] // Initializes blocksplittinglast.
     fn default() -> Self {
         Options {
             verbose: false,
@@ -66,15 +112,156 @@ impl Default for Options {
             blocksplitting: true,
             blocksplittinglast: false,
             blocksplittingmax: 15,
+            verify: false,
+            threads: 1,
+            greedy: false,
+            match_finder: MatchFinderKind::HashChain,
+            seed: 0,
+            trials: 1,
         }
     }
 }
 
+/// Input size above which for_size lowers the amount of iterations.
+const FOR_SIZE_SMALL: usize = 1000000;
+
+/// Input size above which for_size uses the fewest iterations.
+const FOR_SIZE_LARGE: usize = 5000000;
+
+impl Options {
+    /// Returns a builder starting from the default options.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder { options: Options::default() }
+    }
+
+    /// Greedy LZ77 only, without iterative optimization. Block splitting is
+    /// still done.
+    pub fn fast() -> Self {
+        Options { greedy: true, ..Options::default() }
+    }
+
+    /// 1000 iterations and no limit on the amount of blocks. Very slow.
+    pub fn max() -> Self {
+        Options { numiterations: 1000, blocksplittingmax: 0, ..Options::default() }
+    }
+
+    /// Picks the amount of iterations for input of the given size as advised
+    /// for numiterations: 15 for small inputs, 10 up to several MB and 5 above.
+    pub fn for_size(size: usize) -> Self {
+        let numiterations = if size <= FOR_SIZE_SMALL {
+            15
+        } else if size <= FOR_SIZE_LARGE {
+            10
+        } else {
+            5
+        };
+        Options { numiterations, ..Options::default() }
+    }
+
+    /// Checks that the options can be used for compression: unless greedy is
+    /// set, numiterations and trials must be at least 1. Every combination of
+    /// the other fields is valid; settings the chosen mode does not use, such as
+    /// numiterations, seed and trials with greedy, are ignored.
+    pub fn validate(&self) -> Result<(), ZopfliError> {
+        if self.greedy {
+            return Ok(());
+        }
+        if self.numiterations < 1 {
+            return Err(ZopfliError::InvalidOptions("numiterations must be at least 1"));
+        }
+        if self.trials < 1 {
+            return Err(ZopfliError::InvalidOptions("trials must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Builds Options, validating them in build. Obtained from Options::builder.
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    /// Sets Options::verbose.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.options.verbose = verbose;
+        self
+    }
+
+    /// Sets Options::verbose_more.
+    pub fn verbose_more(mut self, verbose_more: bool) -> Self {
+        self.options.verbose_more = verbose_more;
+        self
+    }
+
+    /// Sets Options::numiterations, which must be at least 1.
+    pub fn numiterations(mut self, numiterations: i32) -> Self {
+        self.options.numiterations = numiterations;
+        self
+    }
+
+    /// Sets Options::blocksplitting.
+    pub fn blocksplitting(mut self, blocksplitting: bool) -> Self {
+        self.options.blocksplitting = blocksplitting;
+        self
+    }
+
+    /// Sets Options::blocksplittingmax, 0 for unlimited.
+    pub fn blocksplittingmax(mut self, blocksplittingmax: usize) -> Self {
+        self.options.blocksplittingmax = blocksplittingmax;
+        self
+    }
+
+    /// Sets Options::verify.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.options.verify = verify;
+        self
+    }
+
+    /// Sets Options::threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// Sets Options::greedy.
+    pub fn greedy(mut self, greedy: bool) -> Self {
+        self.options.greedy = greedy;
+        self
+    }
+
+    /// Sets Options::match_finder.
+    pub fn match_finder(mut self, match_finder: MatchFinderKind) -> Self {
+        self.options.match_finder = match_finder;
+        self
+    }
+
+    /// Sets Options::seed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.options.seed = seed;
+        self
+    }
+
+    /// Sets Options::trials, which must be at least 1.
+    pub fn trials(mut self, trials: usize) -> Self {
+        self.options.trials = trials;
+        self
+    }
+
+    /// Returns the options, or an error if they can not be used for
+    /// compression.
+    pub fn build(self) -> Result<Options, ZopfliError> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
 /// Stores lit/length and dist pairs for LZ77.
-/// The memory is managed by init_lz77_store to initialize it,
-/// clean_lz77_store to destroy it, and store_lit_len_dist to append values.
+/// The input data is borrowed rather than copied, so a store never duplicates
+/// the data it was computed from. Values are appended with store_lit_len_dist.
 #[derive(Debug, Clone)]
-pub struct LZ77Store {
+pub struct LZ77Store<'a> {
     /// Literal or length values
     pub litlens: Vec<u16>,
     
@@ -83,7 +270,7 @@ pub struct LZ77Store {
     pub dists: Vec<u16>,
     
     /// Original data reference
-    pub data: Vec<u8>,
+    pub data: &'a [u8],
     
     /// Position in data where this LZ77 command begins
     pub pos: Vec<usize>,
@@ -101,12 +288,12 @@ pub struct LZ77Store {
     pub d_counts: Vec<usize>,
 }
 
-impl LZ77Store {
-    pub fn new(data: &[u8]) -> Self {
+impl<'a> LZ77Store<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
         LZ77Store {
             litlens: Vec::new(),
             dists: Vec::new(),
-            data: data.to_vec(),
+            data,
             pos: Vec::new(),
             ll_symbol: Vec::new(),
             d_symbol: Vec::new(),
@@ -118,6 +305,63 @@ impl LZ77Store {
     pub fn size(&self) -> usize {
         self.litlens.len()
     }
+    
+    /// Appends all lit/len and dist pairs of other, updating the cumulative
+    /// histograms as if they were stored one by one. other must be a store over
+    /// the same data, since its positions are copied as they are.
+    pub fn append(&mut self, other: &LZ77Store) {
+        debug_assert!(std::ptr::eq(self.data, other.data));
+        for i in 0..other.size() {
+            store_lit_len_dist(other.litlens[i], other.dists[i], other.pos[i], self);
+        }
+    }
+    
+    /// Shortens the store to its first len entries, removing the later entries
+    /// from the cumulative histogram of the last remaining chunk. Has no effect
+    /// if len is not smaller than the current size.
+    pub fn truncate(&mut self, len: usize) {
+        let size = self.size();
+        if len >= size {
+            return;
+        }
+        let ll_len = NUM_LL * len.div_ceil(NUM_LL);
+        let d_len = NUM_D * len.div_ceil(NUM_D);
+        for i in len..size {
+            let llstart = NUM_LL * (i / NUM_LL);
+            if llstart < ll_len {
+                self.ll_counts[llstart + self.ll_symbol[i] as usize] -= 1;
+            }
+            let dstart = NUM_D * (i / NUM_D);
+            if dstart < d_len && self.dists[i] != 0 {
+                self.d_counts[dstart + self.d_symbol[i] as usize] -= 1;
+            }
+        }
+        
+        self.litlens.truncate(len);
+        self.dists.truncate(len);
+        self.pos.truncate(len);
+        self.ll_symbol.truncate(len);
+        self.d_symbol.truncate(len);
+        self.ll_counts.truncate(ll_len);
+        self.d_counts.truncate(d_len);
+    }
+    
+    /// Removes all entries, keeping the data reference and allocations.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+    
+    /// Returns a new store with a copy of the entries in the given range of
+    /// this one, with histograms recomputed from the beginning of the range, at
+    /// a cost linear in its length. The data is borrowed as well, so positions
+    /// stay valid.
+    pub fn to_sub_store(&self, range: Range<usize>) -> LZ77Store<'a> {
+        let mut store = LZ77Store::new(self.data);
+        for i in range {
+            store_lit_len_dist(self.litlens[i], self.dists[i], self.pos[i], &mut store);
+        }
+        store
+    }
 }
 
 /// Symbol statistics for Huffman encoding
@@ -160,6 +404,23 @@ impl Default for RanState {
     }
 }
 
+impl RanState {
+    /// Returns the state for the given seed. Seed 0 gives the default state of
+    /// the C code, other seeds are mixed with SplitMix64 so that nearby seeds
+    /// give unrelated sequences.
+    pub fn from_seed(seed: u64) -> Self {
+        if seed == 0 {
+            return RanState::default();
+        }
+        let mut x = seed.wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+        // A zero half would keep the multiply-with-carry generator at zero.
+        RanState { m_w: (x as u32) | 1, m_z: ((x >> 32) as u32) | 1 }
+    }
+}
+
 /// Hash table for LZ77 pattern matching
 #[derive(Debug)]
 pub struct Hash {
@@ -301,7 +562,7 @@ impl<'a> BlockState<'a> {
         blockend: usize,
         add_lmc: bool,
     ) -> Self {
-        let blocksize = blockend - blockstart;
+        let blocksize = blockend.saturating_sub(blockstart);
         BlockState {
             options,
             lmc: if add_lmc {
@@ -318,7 +579,7 @@ impl<'a> BlockState<'a> {
 /// Context for split cost calculation
 #[derive(Debug)]
 pub struct SplitCostContext<'a> {
-    pub lz77: &'a LZ77Store,
+    pub lz77: &'a LZ77Store<'a>,
     pub start: usize,
     pub end: usize,
 }
@@ -327,6 +588,48 @@ pub struct SplitCostContext<'a> {
 mod tests {
     use super::*;
     
+    #[test]
+    fn test_options_builder() {
+        let options = Options::builder().numiterations(3).threads(4).verify(true).build().unwrap();
+        assert_eq!(options.numiterations, 3);
+        assert_eq!(options.threads, 4);
+        assert!(options.verify);
+        assert_eq!(options.blocksplittingmax, Options::default().blocksplittingmax);
+        assert!(matches!(
+            Options::builder().numiterations(-1).build(),
+            Err(ZopfliError::InvalidOptions(_))
+        ));
+        let options = Options::builder().seed(42).trials(3).build().unwrap();
+        assert_eq!((options.seed, options.trials), (42, 3));
+        assert!(matches!(Options::builder().trials(0).build(), Err(ZopfliError::InvalidOptions(_))));
+        // Greedy ignores the settings of the iterative optimization.
+        assert!(Options::builder().greedy(true).numiterations(0).trials(0).build().is_ok());
+    }
+    
+    #[test]
+    fn test_ran_state_from_seed() {
+        let default = RanState::default();
+        let zero = RanState::from_seed(0);
+        assert_eq!((zero.m_w, zero.m_z), (default.m_w, default.m_z));
+        let a = RanState::from_seed(1);
+        let b = RanState::from_seed(2);
+        assert_ne!((a.m_w, a.m_z), (b.m_w, b.m_z));
+        assert!(a.m_w != 0 && a.m_z != 0);
+    }
+    
+    #[test]
+    fn test_presets() {
+        assert!(Options::fast().greedy);
+        assert_eq!(Options::max().numiterations, 1000);
+        assert_eq!(Options::max().blocksplittingmax, 0);
+        assert_eq!(Options::for_size(1000).numiterations, 15);
+        assert_eq!(Options::for_size(3000000).numiterations, 10);
+        assert_eq!(Options::for_size(50000000).numiterations, 5);
+        for options in [Options::fast(), Options::max(), Options::for_size(0)] {
+            assert!(options.validate().is_ok());
+        }
+    }
+    
     #[test]
     fn test_constants() {
         assert_eq!(NUM_LL, 288);
@@ -352,8 +655,134 @@ mod tests {
         let store = LZ77Store::new(&data);
         assert_eq!(store.size(), 0);
         assert_eq!(store.data.len(), 5);
+        // The input is borrowed, not copied.
+        assert!(std::ptr::eq(store.data, &data[..]));
     }
-    
+
+    /// Stores n pseudo-random literals and matches. The positions are not
+    /// meaningful, only the symbols matter for the histograms.
+    fn random_store(data: &[u8], n: usize, seed: u32) -> LZ77Store<'_> {
+        let mut store = LZ77Store::new(data);
+        let mut x = seed;
+        for i in 0..n {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            let r = (x >> 8) as usize;
+            if r.is_multiple_of(3) {
+                store_lit_len_dist((r % 256) as u16, 0, i, &mut store);
+            } else {
+                let length = (MIN_MATCH + r % (MAX_MATCH - MIN_MATCH + 1)) as u16;
+                let dist = (1 + (r >> 8) % WINDOW_SIZE) as u16;
+                store_lit_len_dist(length, dist, i, &mut store);
+            }
+        }
+        store
+    }
+
+    /// Checks the invariants lz77_get_histogram relies on: one cumulative chunk
+    /// per started group of NUM_LL (NUM_D) entries, and histograms of any range
+    /// equal to counting the symbols directly.
+    fn assert_histogram_invariants(store: &LZ77Store) {
+        let size = store.size();
+        assert_eq!(store.ll_counts.len(), NUM_LL * size.div_ceil(NUM_LL));
+        assert_eq!(store.d_counts.len(), NUM_D * size.div_ceil(NUM_D));
+
+        let mut ranges = vec![(0, size)];
+        for lstart in (0..size).step_by(97) {
+            for lend in (lstart..=size).step_by(131) {
+                ranges.push((lstart, lend));
+            }
+            ranges.push((lstart, size));
+        }
+        for (lstart, lend) in ranges {
+            let mut ll_expected = [0usize; NUM_LL];
+            let mut d_expected = [0usize; NUM_D];
+            for i in lstart..lend {
+                ll_expected[store.ll_symbol[i] as usize] += 1;
+                if store.dists[i] != 0 {
+                    d_expected[store.d_symbol[i] as usize] += 1;
+                }
+            }
+            let mut ll_counts = [0usize; NUM_LL];
+            let mut d_counts = [0usize; NUM_D];
+            crate::block::lz77_get_histogram(store, lstart, lend, &mut ll_counts, &mut d_counts);
+            assert_eq!(ll_counts, ll_expected, "range {}..{}", lstart, lend);
+            assert_eq!(d_counts, d_expected, "range {}..{}", lstart, lend);
+        }
+    }
+
+    fn assert_same_entries(a: &LZ77Store, b: &LZ77Store) {
+        assert_eq!(a.litlens, b.litlens);
+        assert_eq!(a.dists, b.dists);
+        assert_eq!(a.pos, b.pos);
+        assert_eq!(a.ll_symbol, b.ll_symbol);
+        assert_eq!(a.d_symbol, b.d_symbol);
+        assert_eq!(a.ll_counts, b.ll_counts);
+        assert_eq!(a.d_counts, b.d_counts);
+    }
+
+    #[test]
+    fn test_lz77_store_append() {
+        let data = [0u8; 0];
+        let whole = random_store(&data, 1500, 1);
+        assert_histogram_invariants(&whole);
+
+        // Appending at sizes that are not multiples of the chunk sizes.
+        let mut store = whole.to_sub_store(0..301);
+        store.append(&whole.to_sub_store(301..1000));
+        store.append(&whole.to_sub_store(1000..1500));
+        assert_histogram_invariants(&store);
+        assert_same_entries(&store, &whole);
+
+        let mut empty = LZ77Store::new(&data);
+        empty.append(&LZ77Store::new(&data));
+        assert_eq!(empty.size(), 0);
+        assert_histogram_invariants(&empty);
+    }
+
+    #[test]
+    fn test_lz77_store_truncate() {
+        let data = [0u8; 0];
+        let whole = random_store(&data, 1200, 2);
+        for len in [1100, 864, 577, 576, 300, 288, 33, 32, 1, 0] {
+            let mut store = whole.clone();
+            store.truncate(len);
+            assert_eq!(store.size(), len);
+            assert_histogram_invariants(&store);
+            assert_same_entries(&store, &whole.to_sub_store(0..len));
+
+            // Storing more after truncating continues the histograms correctly.
+            store.append(&whole.to_sub_store(len..1200));
+            assert_same_entries(&store, &whole);
+        }
+
+        let mut store = whole.clone();
+        store.truncate(5000);
+        assert_eq!(store.size(), 1200);
+    }
+
+    #[test]
+    fn test_lz77_store_clear() {
+        let data = [1u8, 2, 3];
+        let mut store = random_store(&data, 700, 3);
+        store.clear();
+        assert_eq!(store.size(), 0);
+        assert!(store.ll_counts.is_empty() && store.d_counts.is_empty());
+        assert!(std::ptr::eq(store.data, &data[..]));
+        assert_histogram_invariants(&store);
+    }
+
+    #[test]
+    fn test_lz77_store_to_sub_store() {
+        let data = [0u8; 0];
+        let whole = random_store(&data, 1000, 4);
+        let sub = whole.to_sub_store(250..900);
+        assert_eq!(sub.size(), 650);
+        assert_eq!(sub.litlens[..], whole.litlens[250..900]);
+        assert_eq!(sub.pos[0], whole.pos[250]);
+        assert_histogram_invariants(&sub);
+        assert_eq!(whole.to_sub_store(10..10).size(), 0);
+    }
+
     #[test]
     fn test_hash_new() {
         let hash = Hash::new(WINDOW_SIZE);
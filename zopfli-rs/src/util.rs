@@ -4,11 +4,7 @@
 /// Returns absolute difference between two values
 #[inline]
 pub fn abs_diff(x: usize, y: usize) -> usize {
-    if x > y {
-        x - y
-    } else {
-        y - x
-    }
+    x.abs_diff(y)
 }
 
 /// Returns minimum of two values
@@ -20,7 +16,7 @@ pub fn zopfli_min(a: usize, b: usize) -> usize {
 /// Ceiling division
 #[inline]
 pub fn ceil_div(a: usize, b: usize) -> usize {
-    (a + b - 1) / b
+    a.div_ceil(b)
 }
 
 #[cfg(test)]
@@ -0,0 +1,221 @@
+// Copyright Anysphere Inc.
+// Verification of compressed output by decoding it again
+
+use std::fmt;
+
+use crate::encoder::Trailer;
+use crate::inflate::{inflate_blocks, inflate_part, InflateError};
+
+/// Describes how a compressed stream failed to reproduce its input. Blocks are
+/// numbered from 0 in the order of the deflate stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The deflate stream could not be decoded.
+    Decode {
+        /// Index of the block that failed to decode.
+        block: usize,
+        /// The decoder error.
+        error: InflateError,
+    },
+    /// The decoded data differs from the input.
+    Mismatch {
+        /// Index of the block containing the first differing byte.
+        block: usize,
+        /// Uncompressed position of the first differing byte. Equal to the
+        /// shorter length if one of the two is a prefix of the other.
+        position: usize,
+        /// Length of the input.
+        expected_size: usize,
+        /// Length of the decoded data.
+        actual_size: usize,
+    },
+    /// A streaming compressor set the final bit on a block before the end of
+    /// the input.
+    EarlyEnd {
+        /// Index of the block with the final bit.
+        block: usize,
+    },
+    /// The gzip or zlib trailer does not match the decoded data.
+    Trailer,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Decode { block, error } => {
+                write!(f, "verification failed: block {} does not decode: {}", block, error)
+            }
+            VerifyError::Mismatch { block, position, expected_size, actual_size } => write!(
+                f,
+                "verification failed: block {} diverges from the input at byte {} (input size {}, decoded size {})",
+                block, position, expected_size, actual_size
+            ),
+            VerifyError::EarlyEnd { block } => {
+                write!(f, "verification failed: block {} ends the stream before the end of the input", block)
+            }
+            VerifyError::Trailer => f.write_str("verification failed: the trailer does not match the decoded data"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Decodes the raw deflate stream, compressed with the given preset dictionary,
+/// and checks that it reproduces input exactly.
+pub fn verify_deflate(dictionary: &[u8], compressed: &[u8], input: &[u8]) -> Result<(), VerifyError> {
+    let mut block_ends = Vec::new();
+    let decoded = inflate_blocks(dictionary, compressed, &mut block_ends)
+        .map_err(|error| VerifyError::Decode { block: block_ends.len(), error })?;
+    compare(&decoded, input, &block_ends, 0, 0)
+}
+
+/// Checks that decoded, which came from the blocks ending at block_ends, is
+/// equal to input. The first block and byte are numbered firstblock and
+/// firstpos in the error.
+fn compare(
+    decoded: &[u8],
+    input: &[u8],
+    block_ends: &[usize],
+    firstblock: usize,
+    firstpos: usize,
+) -> Result<(), VerifyError> {
+    let position = match decoded.iter().zip(input).position(|(a, b)| a != b) {
+        Some(position) => position,
+        None if decoded.len() == input.len() => return Ok(()),
+        None => decoded.len().min(input.len()),
+    };
+    // The block that produced the byte at position, or the last block if the
+    // decoded data ended early.
+    let block = block_ends
+        .iter()
+        .position(|&end| end > position)
+        .unwrap_or(block_ends.len().saturating_sub(1));
+    Err(VerifyError::Mismatch {
+        block: firstblock + block,
+        position: firstpos + position,
+        expected_size: firstpos + input.len(),
+        actual_size: firstpos + decoded.len(),
+    })
+}
+
+/// Verifies the output of a streaming compressor one master block at a time,
+/// so that no master block is passed on before it is known to decode to its
+/// input. Blocks and positions in the errors count from the start of the
+/// stream.
+pub(crate) struct MasterBlockVerifier {
+    /// Number of deflate blocks verified so far.
+    blocks: usize,
+    /// Number of input bytes verified so far.
+    size: usize,
+    /// Checksum of the data decoded so far.
+    trailer: Trailer,
+}
+
+impl MasterBlockVerifier {
+    /// Creates a verifier for a stream ending with the given empty trailer.
+    pub(crate) fn new(trailer: Trailer) -> Self {
+        MasterBlockVerifier { blocks: 0, size: 0, trailer }
+    }
+
+    /// Decodes the master block starting at bit offset start of compressed,
+    /// with window as the data preceding it, and checks that it reproduces
+    /// input. If final_block is true it must end the deflate stream, followed
+    /// by the trailer at the next byte boundary and nothing else.
+    pub(crate) fn verify(
+        &mut self,
+        window: &[u8],
+        compressed: &[u8],
+        start: usize,
+        input: &[u8],
+        final_block: bool,
+    ) -> Result<(), VerifyError> {
+        let size = if final_block { usize::MAX } else { input.len() };
+        let mut block_ends = Vec::new();
+        let (decoded, end, last) = inflate_part(window, compressed, start, size, &mut block_ends)
+            .map_err(|error| VerifyError::Decode { block: self.blocks + block_ends.len(), error })?;
+        compare(&decoded, input, &block_ends, self.blocks, self.size)?;
+        if last && !final_block {
+            return Err(VerifyError::EarlyEnd { block: self.blocks + block_ends.len() - 1 });
+        }
+        self.blocks += block_ends.len();
+        self.size += decoded.len();
+        self.trailer.update(&decoded);
+
+        if final_block && compressed[end.div_ceil(8)..] != self.trailer.bytes()[..] {
+            return Err(VerifyError::Trailer);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::deflate;
+    use crate::types::Options;
+
+    /// Two stored blocks, "ab" then "c".
+    const TWO_BLOCKS: [u8; 13] = [0x00, 0x02, 0x00, 0xfd, 0xff, b'a', b'b', 0x01, 0x01, 0x00, 0xfe, 0xff, b'c'];
+
+    #[test]
+    fn test_verify_ok() {
+        let data = b"verify verify verify".repeat(30);
+        let compressed = deflate(&Options::default(), 2, &data).unwrap();
+        assert_eq!(verify_deflate(&[], &compressed, &data), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_mismatch_names_block() {
+        let err = verify_deflate(&[], &TWO_BLOCKS, b"abd").unwrap_err();
+        assert_eq!(err, VerifyError::Mismatch { block: 1, position: 2, expected_size: 3, actual_size: 3 });
+
+        let err = verify_deflate(&[], &TWO_BLOCKS, b"xbc").unwrap_err();
+        assert!(matches!(err, VerifyError::Mismatch { block: 0, position: 0, .. }));
+
+        // Decoded data is missing a byte at the end.
+        let err = verify_deflate(&[], &TWO_BLOCKS, b"abcd").unwrap_err();
+        assert!(matches!(err, VerifyError::Mismatch { block: 1, position: 3, .. }));
+    }
+
+    #[test]
+    fn test_verify_decode_error_names_block() {
+        let err = verify_deflate(&[], &TWO_BLOCKS[..10], b"abc").unwrap_err();
+        assert_eq!(err, VerifyError::Decode { block: 1, error: InflateError::UnexpectedEof });
+    }
+
+    #[test]
+    fn test_master_block_verifier() {
+        // The second block starts at byte 7.
+        let mut verifier = MasterBlockVerifier::new(Trailer::None);
+        assert_eq!(verifier.verify(&[], &TWO_BLOCKS, 0, b"ab", false), Ok(()));
+        assert_eq!(verifier.verify(b"ab", &TWO_BLOCKS, 56, b"c", true), Ok(()));
+
+        // Positions count from the start of the stream.
+        let mut verifier = MasterBlockVerifier::new(Trailer::None);
+        verifier.verify(&[], &TWO_BLOCKS, 0, b"ab", false).unwrap();
+        let err = verifier.verify(b"ab", &TWO_BLOCKS, 56, b"d", true).unwrap_err();
+        assert_eq!(err, VerifyError::Mismatch { block: 1, position: 2, expected_size: 3, actual_size: 3 });
+
+        let mut verifier = MasterBlockVerifier::new(Trailer::None);
+        let err = verifier.verify(&[], &TWO_BLOCKS[7..], 0, b"c", false).unwrap_err();
+        assert_eq!(err, VerifyError::EarlyEnd { block: 0 });
+    }
+
+    #[test]
+    fn test_master_block_verifier_trailer() {
+        let mut trailer = Trailer::Zlib { adler: 1 };
+        trailer.update(b"abc");
+        let mut stream = TWO_BLOCKS.to_vec();
+        stream.extend_from_slice(&trailer.bytes());
+
+        let mut verifier = MasterBlockVerifier::new(Trailer::Zlib { adler: 1 });
+        verifier.verify(&[], &stream, 0, b"ab", false).unwrap();
+        assert_eq!(verifier.verify(b"ab", &stream, 56, b"c", true), Ok(()));
+
+        let n = stream.len();
+        stream[n - 1] ^= 1;
+        let mut verifier = MasterBlockVerifier::new(Trailer::Zlib { adler: 1 });
+        verifier.verify(&[], &stream, 0, b"ab", false).unwrap();
+        assert_eq!(verifier.verify(b"ab", &stream, 56, b"c", true), Err(VerifyError::Trailer));
+    }
+}
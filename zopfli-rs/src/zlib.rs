@@ -0,0 +1,154 @@
+// Copyright Anysphere Inc.
+// zlib (RFC 1950) container output
+
+use crate::types::Options;
+use crate::deflate::deflate_with_dictionary;
+use crate::error::ZopfliError;
+
+/// Largest prime smaller than 65536.
+const ADLER_BASE: u32 = 65521;
+
+/// Largest amount of bytes that can be summed before the sums must be reduced
+/// modulo ADLER_BASE to avoid overflowing 32 bits.
+const SUMS_OVERFLOW: usize = 5550;
+
+/// Updates a running Adler-32 checksum with the bytes data. The checksum should
+/// be initialized to 1, and the result of each call passed to the next one.
+pub fn update_adler32(adler: u32, data: &[u8]) -> u32 {
+    let mut s1 = adler & 0xffff;
+    let mut s2 = adler >> 16;
+    for chunk in data.chunks(SUMS_OVERFLOW) {
+        for &byte in chunk {
+            s1 += byte as u32;
+            s2 += s1;
+        }
+        s1 %= ADLER_BASE;
+        s2 %= ADLER_BASE;
+    }
+    (s2 << 16) | s1
+}
+
+/// Returns the Adler-32 checksum of the bytes data.
+pub fn adler32(data: &[u8]) -> u32 {
+    update_adler32(1, data)
+}
+
+/// Appends the two byte zlib header to out, followed by the big-endian
+/// dictionary id if dictid is given.
+/// CM is 8 (deflate) with a 32K window, and FLEVEL is 3 since zopfli always
+/// uses the maximum compression.
+pub fn write_zlib_header(dictid: Option<u32>, out: &mut Vec<u8>) {
+    let cmf: u32 = 120; // CM 8, CINFO 7. See zlib spec.
+    let flevel: u32 = 3;
+    let fdict: u32 = dictid.is_some() as u32;
+    let mut cmfflg = 256 * cmf + fdict * 32 + flevel * 64;
+    let fcheck = 31 - cmfflg % 31;
+    cmfflg += fcheck;
+
+    out.push((cmfflg / 256) as u8);
+    out.push((cmfflg % 256) as u8);
+    if let Some(dictid) = dictid {
+        out.extend_from_slice(&dictid.to_be_bytes()); // DICTID
+    }
+}
+
+/// Appends the zlib trailer, the big-endian Adler-32 of the uncompressed data.
+pub fn write_zlib_trailer(checksum: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&checksum.to_be_bytes());
+}
+
+/// Compresses the data according to the zlib specification, RFC 1950.
+pub fn zlib_compress(options: &Options, input: &[u8]) -> Result<Vec<u8>, ZopfliError> {
+    zlib_compress_with_dictionary(options, None, input)
+}
+
+/// Compresses the data according to the zlib specification, RFC 1950. If a
+/// preset dictionary is given, the FDICT flag and its Adler-32 are written to
+/// the header and matches may refer back into the dictionary; the decompressor
+/// must then be given the same dictionary.
+pub fn zlib_compress_with_dictionary(
+    options: &Options,
+    dictionary: Option<&[u8]>,
+    input: &[u8],
+) -> Result<Vec<u8>, ZopfliError> {
+    let mut out = Vec::new();
+    write_zlib_header(dictionary.map(adler32), &mut out);
+    out.extend_from_slice(&deflate_with_dictionary(options, 2, dictionary.unwrap_or(&[]), input)?);
+    write_zlib_trailer(adler32(input), &mut out);
+
+    if options.verbose {
+        eprintln!(
+            "Original Size: {}, Zlib: {}, Compression: {:.2}% Removed",
+            input.len(), out.len(),
+            100.0 * (input.len() as f64 - out.len() as f64) / input.len() as f64
+        );
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(b"123456789"), 0x091e01de);
+    }
+
+    #[test]
+    fn test_adler32_large_input() {
+        // Exercises the modulo reduction across many chunks.
+        let data = vec![0xff; 100000];
+        let mut s1: u64 = 1;
+        let mut s2: u64 = 0;
+        for &b in &data {
+            s1 = (s1 + b as u64) % 65521;
+            s2 = (s2 + s1) % 65521;
+        }
+        assert_eq!(adler32(&data), ((s2 << 16) | s1) as u32);
+    }
+
+    #[test]
+    fn test_update_adler32_incremental() {
+        let data = b"incremental adler over several pieces";
+        let adler = data.chunks(5).fold(1, update_adler32);
+        assert_eq!(adler, adler32(data));
+    }
+
+    #[test]
+    fn test_header() {
+        let mut out = Vec::new();
+        write_zlib_header(None, &mut out);
+        assert_eq!(out, vec![0x78, 0xda]);
+        assert_eq!((out[0] as u32 * 256 + out[1] as u32) % 31, 0);
+
+        let mut out = Vec::new();
+        write_zlib_header(Some(0x01020304), &mut out);
+        assert_eq!(out[1] & 0x20, 0x20);
+        assert_eq!((out[0] as u32 * 256 + out[1] as u32) % 31, 0);
+        assert_eq!(&out[2..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_zlib_compress_trailer() {
+        let data = b"zlib zlib zlib zlib";
+        let out = zlib_compress(&Options::default(), data).unwrap();
+        let n = out.len();
+        assert_eq!(&out[..2], &[0x78, 0xda]);
+        assert_eq!(&out[n - 4..], &adler32(data).to_be_bytes());
+    }
+
+    #[test]
+    fn test_zlib_compress_with_dictionary() {
+        let dictionary = b"the quick brown fox jumps over the lazy dog";
+        let data = b"the lazy dog jumps over the quick brown fox";
+        let with = zlib_compress_with_dictionary(&Options::default(), Some(dictionary), data).unwrap();
+        let without = zlib_compress(&Options::default(), data).unwrap();
+        assert_eq!(&with[2..6], &adler32(dictionary).to_be_bytes());
+        // Matches into the dictionary make the deflate stream smaller.
+        assert!(with.len() - 4 < without.len());
+    }
+}
@@ -20,7 +20,7 @@ fn get_c_output(input: &str) -> Vec<u8> {
 }
 
 fn compare_with_c(input: &str, test_name: &str) {
-    let rust_output = deflate_greedy_fixed(input.as_bytes());
+    let rust_output = deflate_greedy_fixed(input.as_bytes()).unwrap();
     let c_output = get_c_output(input);
     
     println!("\n=== {} ===", test_name);
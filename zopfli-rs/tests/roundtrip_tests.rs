@@ -1,21 +1,17 @@
 // Copyright Anysphere Inc.
-// Roundtrip tests - compress with our code, decompress with flate2
+// Roundtrip tests - compress with our code, decompress with our inflate
 
-use flate2::read::DeflateDecoder;
-use std::io::Read;
 use zopfli_rs::deflate::deflate_greedy_fixed;
+use zopfli_rs::inflate::{inflate, InflateError};
 
-fn decompress_deflate(compressed: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut decoder = DeflateDecoder::new(compressed);
-    let mut decompressed = Vec::new();
-    decoder.read_to_end(&mut decompressed)?;
-    Ok(decompressed)
+fn decompress_deflate(compressed: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate(compressed)
 }
 
 #[test]
 fn test_roundtrip_hello_world() {
     let original = b"hello world";
-    let compressed = deflate_greedy_fixed(original);
+    let compressed = deflate_greedy_fixed(original).unwrap();
     
     println!("Original: {} bytes", original.len());
     println!("Compressed: {} bytes", compressed.len());
@@ -35,7 +31,7 @@ fn test_roundtrip_hello_world() {
 #[test]
 fn test_roundtrip_repeated_pattern() {
     let original = b"aaaaaaaaaa";
-    let compressed = deflate_greedy_fixed(original);
+    let compressed = deflate_greedy_fixed(original).unwrap();
     
     println!("Original: {} bytes ({})", original.len(), std::str::from_utf8(original).unwrap());
     println!("Compressed: {} bytes", compressed.len());
@@ -54,7 +50,7 @@ fn test_roundtrip_repeated_pattern() {
 #[test]
 fn test_roundtrip_hhhheeeeellllloooooo() {
     let original = b"hhhheeeeellllloooooo";
-    let compressed = deflate_greedy_fixed(original);
+    let compressed = deflate_greedy_fixed(original).unwrap();
     
     println!("Original: {} bytes ({})", original.len(), std::str::from_utf8(original).unwrap());
     println!("Compressed: {} bytes", compressed.len());
@@ -73,7 +69,7 @@ fn test_roundtrip_hhhheeeeellllloooooo() {
 #[test]
 fn test_roundtrip_hello_worldaaaaaaaaa() {
     let original = b"hello worldaaaaaaaaa";
-    let compressed = deflate_greedy_fixed(original);
+    let compressed = deflate_greedy_fixed(original).unwrap();
     
     println!("Original: {} bytes ({})", original.len(), std::str::from_utf8(original).unwrap());
     println!("Compressed: {} bytes", compressed.len());
@@ -92,7 +88,7 @@ fn test_roundtrip_hello_worldaaaaaaaaa() {
 #[test]
 fn test_roundtrip_empty() {
     let original = b"";
-    let compressed = deflate_greedy_fixed(original);
+    let compressed = deflate_greedy_fixed(original).unwrap();
     
     match decompress_deflate(&compressed) {
         Ok(decompressed) => {
@@ -108,7 +104,7 @@ fn test_roundtrip_empty() {
 #[test]
 fn test_roundtrip_single_byte() {
     let original = b"a";
-    let compressed = deflate_greedy_fixed(original);
+    let compressed = deflate_greedy_fixed(original).unwrap();
     
     match decompress_deflate(&compressed) {
         Ok(decompressed) => {
@@ -131,7 +127,7 @@ proptest! {
             return Ok(()); // Skip very large inputs
         }
         
-        let compressed = deflate_greedy_fixed(original);
+        let compressed = deflate_greedy_fixed(original).unwrap();
         
         match decompress_deflate(&compressed) {
             Ok(decompressed) => {
@@ -150,7 +146,7 @@ proptest! {
     #[test]
     fn test_roundtrip_property_bytes(data in prop::collection::vec(any::<u8>(), 0..100)) {
         let original = &data[..];
-        let compressed = deflate_greedy_fixed(original);
+        let compressed = deflate_greedy_fixed(original).unwrap();
         
         match decompress_deflate(&compressed) {
             Ok(decompressed) => {